@@ -1,21 +1,200 @@
 use criterion::{Criterion, criterion_group, criterion_main};
 use jxl_winthumb::JXLWICBitmapDecoder;
+use windows as Windows;
+use windows::Win32::Foundation::{BOOL, E_NOTIMPL};
 use windows::Win32::Graphics::Imaging::*;
-use windows::Win32::System::Com::CoInitialize;
-use windows::Win32::UI::Shell::SHCreateMemStream;
+use windows::Win32::System::Com::{
+    CLSCTX_INPROC_SERVER, CoCreateInstance, CoInitialize, ISequentialStream_Impl, IStream,
+    IStream_Impl, LOCKTYPE, STATFLAG, STATSTG, STGC, STGM_READ, STREAM_SEEK,
+};
+use windows::Win32::UI::Shell::{SHCreateMemStream, SHCreateStreamOnFileEx};
+use windows::core::{HRESULT, HSTRING, Interface, implement};
 
-fn basic() {
-    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+/// Forwards to another stream but has no size, so the decoder can only read it in chunks.
+#[implement(Windows::Win32::System::Com::IStream)]
+struct UnsizedStream {
+    inner: IStream,
+}
+
+impl ISequentialStream_Impl for UnsizedStream_Impl {
+    fn Read(&self, pv: *mut core::ffi::c_void, cb: u32, pcbread: *mut u32) -> HRESULT {
+        unsafe { self.inner.Read(pv, cb, Some(pcbread)) }
+    }
+
+    fn Write(&self, _pv: *const core::ffi::c_void, _cb: u32, _pcbwritten: *mut u32) -> HRESULT {
+        E_NOTIMPL
+    }
+}
+
+impl IStream_Impl for UnsizedStream_Impl {
+    fn Seek(
+        &self,
+        dlibmove: i64,
+        dworigin: STREAM_SEEK,
+        plibnewposition: *mut u64,
+    ) -> windows::core::Result<()> {
+        unsafe { self.inner.Seek(dlibmove, dworigin, Some(plibnewposition)) }
+    }
+
+    fn SetSize(&self, _libnewsize: u64) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn CopyTo(
+        &self,
+        _pstm: Option<&IStream>,
+        _cb: u64,
+        _pcbread: *mut u64,
+        _pcbwritten: *mut u64,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Commit(&self, _grfcommitflags: &STGC) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
 
+    fn Revert(&self) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn LockRegion(
+        &self,
+        _liboffset: u64,
+        _cb: u64,
+        _dwlocktype: &LOCKTYPE,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn UnlockRegion(
+        &self,
+        _liboffset: u64,
+        _cb: u64,
+        _dwlocktype: u32,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Stat(&self, _pstatstg: *mut STATSTG, _grfstatflag: &STATFLAG) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Clone(&self) -> windows::core::Result<IStream> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+fn memory_stream() -> IStream {
     let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
-    let stream = unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream");
+    unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream")
+}
+
+fn decode_stream(stream: &IStream) -> IWICBitmapFrameDecode {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
     let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
-    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
-    unsafe { decoder.GetFrame(0) }.expect("Get the first frame");
+    unsafe { decoder.Initialize(stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
+    unsafe { decoder.GetFrame(0) }.expect("Get the first frame")
+}
+
+fn decode_frame() -> IWICBitmapFrameDecode {
+    decode_stream(&memory_stream())
+}
+
+/// A memory stream, which is read into one buffer at once. The frame is rendered when the
+/// pixels are copied, in 32bppBGRA straight from the decoder as Explorer gets it.
+fn basic() {
+    copy_frame(&decode_frame());
+}
+
+/// A file stream, whose file is mapped.
+fn file_stream() {
+    let stream = unsafe {
+        SHCreateStreamOnFileEx(
+            &HSTRING::from("tests/alien.jxl"),
+            STGM_READ.0,
+            0,
+            BOOL::from(false),
+            None::<&IStream>,
+        )
+    }
+    .expect("Create a file IStream");
+    copy_frame(&decode_stream(&stream));
+}
+
+/// A stream without a size, which is read in chunks.
+fn unsized_stream() {
+    let stream: IStream = UnsizedStream {
+        inner: memory_stream(),
+    }
+    .into();
+    copy_frame(&decode_stream(&stream));
+}
+
+fn copy_pixels(source: &IWICBitmapSource) {
+    let mut width = 0u32;
+    let mut height = 0u32;
+    unsafe { source.GetSize(&mut width, &mut height) }.expect("GetSize");
+    let stride = width * 4;
+    let mut pixels: Vec<u8> = vec![0; (stride * height) as usize];
+    unsafe { source.CopyPixels(std::ptr::null(), stride, &mut pixels) }.expect("Copy pixels");
+}
+
+fn copy_frame(frame: &IWICBitmapFrameDecode) {
+    copy_pixels(&frame.cast().expect("Cast to the bitmap source"));
+}
+
+/// 32bppBGRA through a WIC converter from the 16 bit format, as it was before the decoder
+/// could write 8 bit formats.
+fn converted_bgra() {
+    let frame = decode_frame();
+    let transform: IWICBitmapSourceTransform = frame.cast().expect("Cast to the transform");
+
+    let mut width = 0u32;
+    let mut height = 0u32;
+    unsafe { frame.GetSize(&mut width, &mut height) }.expect("GetSize");
+
+    let (format, stride, pixels) = [
+        (GUID_WICPixelFormat64bppRGBA, 8),
+        (GUID_WICPixelFormat48bppRGB, 6),
+        (GUID_WICPixelFormat16bppGray, 2),
+    ]
+    .into_iter()
+    .find_map(|(format, bytes_per_pixel)| {
+        let stride = width * bytes_per_pixel;
+        let mut pixels: Vec<u8> = vec![0; (stride * height) as usize];
+        unsafe {
+            transform.CopyPixels(
+                std::ptr::null(),
+                width,
+                height,
+                &format,
+                WICBitmapTransformRotate0,
+                stride,
+                &mut pixels,
+            )
+        }
+        .ok()?;
+        Some((format, stride, pixels))
+    })
+    .expect("Copy pixels in a 16 bit format");
+
+    let factory: IWICImagingFactory =
+        unsafe { CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER) }
+            .expect("Create a factory");
+    let bitmap = unsafe { factory.CreateBitmapFromMemory(width, height, &format, stride, &pixels) }
+        .expect("Create a bitmap");
+    let source = unsafe { WICConvertBitmapSource(&GUID_WICPixelFormat32bppBGRA, &bitmap) }
+        .expect("Convert the bitmap");
+    copy_pixels(&source);
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("alien.jxl", |b| b.iter(basic));
+    c.bench_function("alien.jxl file stream", |b| b.iter(file_stream));
+    c.bench_function("alien.jxl unsized stream", |b| b.iter(unsized_stream));
+    c.bench_function("alien.jxl converted 32bppBGRA", |b| b.iter(converted_bgra));
 }
 
 criterion_group!(benches, criterion_benchmark);
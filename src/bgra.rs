@@ -0,0 +1,75 @@
+// Converts 16 bit frames to the 8 bit BGRA that Explorer and most WIC consumers ask for,
+// so that they don't need a format converter of their own.
+
+/// How the samples are written into the caller buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// The 16 bit samples as they are in the frame buffer
+    Native,
+    /// 32bppBGRA, or 32bppPBGRA if premultiplied
+    Bgra { premultiplied: bool, dither: bool },
+}
+
+impl Output {
+    pub fn bytes_per_pixel(&self, channels: usize) -> usize {
+        match self {
+            Output::Native => channels * std::mem::size_of::<u16>(),
+            Output::Bgra { .. } => 4,
+        }
+    }
+}
+
+// 4x4 Bayer matrix, in 1/16
+const BAYER: [[u32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Quantizes to 8 bits. With dithering, the rounding threshold follows the Bayer matrix
+/// so that smooth gradients don't band.
+fn to_u8(sample: u32, x: usize, y: usize, dither: bool) -> u8 {
+    // 65535 / 255 == 257
+    let threshold = if dither {
+        (BAYER[y % 4][x % 4] * 2 + 1) * 257 / 32
+    } else {
+        257 / 2
+    };
+    ((sample + threshold) / 257).min(255) as u8
+}
+
+/// Converts a row of gray, RGB, or RGBA samples. `x` and `y` are the position of the first
+/// pixel in the frame.
+pub fn convert_row(
+    src: &[u16],
+    channels: usize,
+    dst: &mut [u8],
+    x: usize,
+    y: usize,
+    premultiplied: bool,
+    dither: bool,
+) {
+    for (i, (pixel, out)) in src
+        .chunks_exact(channels)
+        .zip(dst.chunks_exact_mut(4))
+        .enumerate()
+    {
+        let (r, g, b, a) = match *pixel {
+            [l] => (l, l, l, u16::MAX),
+            [l, a] => (l, l, l, a),
+            [r, g, b] => (r, g, b, u16::MAX),
+            [r, g, b, a, ..] => (r, g, b, a),
+            [] => unreachable!("chunks_exact never gives empty chunks"),
+        };
+        let color = |v: u16| {
+            if premultiplied {
+                (v as u32 * a as u32 + u16::MAX as u32 / 2) / u16::MAX as u32
+            } else {
+                v as u32
+            }
+        };
+        let x = x + i;
+        out.copy_from_slice(&[
+            to_u8(color(b), x, y, dither),
+            to_u8(color(g), x, y, dither),
+            to_u8(color(r), x, y, dither),
+            to_u8(a as u32, x, y, false),
+        ]);
+    }
+}
@@ -0,0 +1,44 @@
+// Bounds how long a decode can keep Explorer or dllhost busy. jxl-oxide can't be interrupted
+// in the middle of a render, so the budget is checked between chunks while loading and
+// between tiles while rendering.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use windows::Win32::Foundation::{E_ABORT, ERROR_TIMEOUT};
+
+#[derive(Debug, Clone)]
+pub struct Budget {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Budget {
+    /// Starts a budget of `timeout_ms` milliseconds from now. 0 has no time limit.
+    pub fn new(timeout_ms: u32, cancelled: Arc<AtomicBool>) -> Self {
+        Self {
+            deadline: (timeout_ms > 0)
+                .then(|| Instant::now() + Duration::from_millis(timeout_ms as u64)),
+            cancelled,
+        }
+    }
+
+    /// Fails with E_ABORT when cancelled, or with HRESULT_FROM_WIN32(ERROR_TIMEOUT) when
+    /// the time is up.
+    pub fn check(&self) -> windows::core::Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(windows::core::Error::new(E_ABORT, "Decoding was cancelled"));
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(windows::core::Error::new(
+                ERROR_TIMEOUT.to_hresult(),
+                "Decoding ran out of its time budget",
+            ));
+        }
+        Ok(())
+    }
+}
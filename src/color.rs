@@ -0,0 +1,144 @@
+use std::rc::Rc;
+
+use jxl_oxide::color::{
+    ColourEncoding, EnumColourEncoding, Primaries, RenderingIntent, TransferFunction, WhitePoint,
+};
+use jxl_oxide::{JxlImage, PixelFormat};
+use windows::Win32::Foundation::E_INVALIDARG;
+use windows::Win32::Graphics::Imaging::IWICColorContext;
+
+/// The color space to render SDR images in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetColorSpace {
+    /// The color space the image is encoded in, for wide gamut workflows
+    Original,
+    /// For consumers that drop color profiles, like the thumbnail cache
+    Srgb,
+    DisplayP3,
+}
+
+impl TargetColorSpace {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "original" => Some(TargetColorSpace::Original),
+            "srgb" => Some(TargetColorSpace::Srgb),
+            "displayp3" => Some(TargetColorSpace::DisplayP3),
+            _ => None,
+        }
+    }
+
+    /// Asks jxl-oxide to render in this color space, and returns the color space it will
+    /// actually render in. Images with an ICC profile are converted by the CMS that the
+    /// loader sets.
+    pub fn request(self, image: &mut JxlImage) -> Self {
+        let encoding = match self {
+            TargetColorSpace::Original => return self,
+            TargetColorSpace::Srgb => EnumColourEncoding::srgb(RenderingIntent::Relative),
+            TargetColorSpace::DisplayP3 => {
+                EnumColourEncoding::display_p3(RenderingIntent::Relative)
+            }
+        };
+        image.request_color_encoding(encoding);
+        self
+    }
+}
+
+/// Gray alpha frames are expanded to RGBA, and 8 bit gray frames are written as BGRA, so a
+/// gray profile wouldn't describe them. Such images are rendered as sRGB gray instead, which
+/// is plain sRGB once the gray is copied into the color channels. Returns whether the image
+/// is one of them.
+pub fn request_rgb_gray(image: &mut JxlImage) -> bool {
+    let expanded = match image.pixel_format() {
+        PixelFormat::Graya => true,
+        PixelFormat::Gray => image.image_header().metadata.bit_depth.bits_per_sample() <= 8,
+        _ => false,
+    };
+    if expanded && !is_srgb(image) {
+        log::trace!("request_rgb_gray: rendering as sRGB gray");
+        image.request_color_encoding(EnumColourEncoding::srgb_gray(RenderingIntent::Relative));
+    }
+    expanded
+}
+
+/// Whether the image is encoded in sRGB, as opposed to an ICC profile or another enum color
+/// space.
+pub fn is_srgb(image: &JxlImage) -> bool {
+    match &image.image_header().metadata.colour_encoding {
+        ColourEncoding::Enum(encoding) => {
+            matches!(encoding.white_point, WhitePoint::D65)
+                && matches!(encoding.primaries, Primaries::Srgb)
+                && matches!(encoding.tf, TransferFunction::Srgb)
+        }
+        _ => false,
+    }
+}
+
+/// The color space of the rendered pixels, as reported by GetColorContexts.
+#[derive(Debug, Clone)]
+pub enum ColorProfile {
+    /// sRGB, which WIC knows as the Exif color space 1 without needing a profile
+    Srgb,
+    Icc(Rc<Vec<u8>>),
+}
+
+impl ColorProfile {
+    /// Must be called after the output color encoding is requested, as it describes what
+    /// jxl-oxide renders. `target` is what TargetColorSpace::request returned.
+    pub fn from_image(image: &JxlImage, hdr: bool, target: TargetColorSpace) -> Self {
+        if hdr {
+            // Rendered as linear sRGB, which WIC float formats imply as scRGB. The transform
+            // tone maps to sRGB for integer formats, so sRGB describes every format offered.
+            return ColorProfile::Srgb;
+        }
+        match target {
+            TargetColorSpace::Original => {}
+            TargetColorSpace::Srgb => return ColorProfile::Srgb,
+            TargetColorSpace::DisplayP3 => return ColorProfile::Icc(Rc::new(image.rendered_icc())),
+        }
+        if is_srgb(image) {
+            ColorProfile::Srgb
+        } else {
+            // The embedded profile of an image that isn't XYB encoded, or else what jxl-oxide
+            // renders XYB into, which is not necessarily the embedded profile. Display P3 and
+            // the other enum color spaces get the compact profile that jxl-oxide synthesizes.
+            ColorProfile::Icc(Rc::new(image.rendered_icc()))
+        }
+    }
+
+    fn initialize(&self, context: &IWICColorContext) -> windows::core::Result<()> {
+        match self {
+            ColorProfile::Srgb => unsafe { context.InitializeFromExifColorSpace(1) },
+            ColorProfile::Icc(icc) => unsafe { context.InitializeFromMemory(&icc[..]) },
+        }
+    }
+}
+
+/// Implements GetColorContexts for the decoder and the frames, which always have exactly one
+/// color context. A zero `ccount` only asks for the count.
+pub fn get_color_contexts(
+    profile: &ColorProfile,
+    ccount: u32,
+    ppicolorcontexts: *mut Option<IWICColorContext>,
+    pcactualcount: *mut u32,
+) -> windows::core::Result<()> {
+    if let Some(actual_count) = unsafe { pcactualcount.as_mut() } {
+        *actual_count = 1;
+    }
+    if ccount == 0 {
+        return Ok(());
+    }
+    if ppicolorcontexts.is_null() {
+        return Err(windows::core::Error::new(
+            E_INVALIDARG,
+            "No color context array for a nonzero count",
+        ));
+    }
+
+    let Some(context) = (unsafe { &*ppicolorcontexts }) else {
+        return Err(windows::core::Error::new(
+            E_INVALIDARG,
+            "The color context array has a null entry",
+        ));
+    };
+    profile.initialize(context)
+}
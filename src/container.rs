@@ -0,0 +1,525 @@
+// Walks the boxes of a JXL container so that those jxl-oxide has no use for are seeked over
+// instead of read. jxl-oxide needs the whole codestream to render, so the jxlc and jxlp boxes
+// are still read in full, but large boxes like JPEG reconstruction data or JUMBF are never
+// pulled through the stream. The `xml ` box is also rewritten here for the property handler.
+// https://github.com/libjxl/libjxl/blob/main/doc/format_overview.md#file-format
+
+use std::io::{Read, Seek, SeekFrom};
+
+/// Boxes that jxl-oxide parses or that carry metadata we expose.
+const KEPT_BOXES: [&[u8; 4]; 8] = [
+    b"JXL ", b"ftyp", b"jxll", b"jxli", b"jxlc", b"jxlp", b"Exif", b"xml ",
+];
+
+/// The boxes we expose that can also come Brotli compressed in a `brob` box, which starts
+/// with the type of the box it holds.
+const KEPT_BROB_BOXES: [&[u8; 4]; 2] = [b"Exif", b"xml "];
+
+const BARE_CODESTREAM_SIGNATURE: [u8; 2] = [0xff, 0x0a];
+
+/// The `JXL ` signature box that starts a container
+const CONTAINER_SIGNATURE: [u8; 12] = [
+    0x00, 0x00, 0x00, 0x0c, b'J', b'X', b'L', b' ', 0x0d, 0x0a, 0x87, 0x0a,
+];
+
+/// An `ftyp` box with the `jxl ` brand, version 0, and `jxl ` as the one compatible brand
+const FILE_TYPE: [u8; 20] = [
+    0x00, 0x00, 0x00, 0x14, b'f', b't', b'y', b'p', b'j', b'x', b'l', b' ', 0x00, 0x00, 0x00, 0x00,
+    b'j', b'x', b'l', b' ',
+];
+
+enum State {
+    /// Nothing read yet, so it is not known whether this is a container at all
+    Start,
+    /// At the header of the next box
+    BoxHeader,
+    /// In the payload of a kept box, with the bytes left in it
+    Payload(u64),
+    /// A bare codestream, or the last box that runs to the end of the stream
+    PassThrough,
+    Done,
+}
+
+pub struct BoxFilter<R> {
+    inner: R,
+    state: State,
+    /// Bytes already read from the stream but not yet returned, like a kept box header
+    pending: Vec<u8>,
+    /// Where `pending` continues
+    pending_offset: usize,
+    /// The stream length, to tell a truncated box from one that can be seeked over
+    len: Option<u64>,
+}
+
+impl<R: Read + Seek> BoxFilter<R> {
+    pub fn new(inner: R, len: Option<u64>) -> Self {
+        Self {
+            inner,
+            state: State::Start,
+            pending: Vec::new(),
+            pending_offset: 0,
+            len,
+        }
+    }
+
+    /// Reads until `buf` is full or the stream ends.
+    fn read_full(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.inner.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(count) => filled += count,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(filled)
+    }
+
+    /// Reads a box header, then either keeps the box or seeks past it. `start` is the part
+    /// of the header that was already read.
+    fn next_box(&mut self, start: &[u8]) -> std::io::Result<()> {
+        let mut header = [0u8; 16];
+        header[..start.len()].copy_from_slice(start);
+        let count = start.len() + self.read_full(&mut header[start.len()..8])?;
+        if count < 8 {
+            // Leave a truncated header for jxl-oxide to judge.
+            self.pending = header[..count].to_vec();
+            self.state = State::Done;
+            return Ok(());
+        }
+
+        let box_type: [u8; 4] = header[4..8].try_into().unwrap();
+        let mut header_len = 8;
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => None,
+            1 => {
+                let count = self.read_full(&mut header[8..16])?;
+                if count < 8 {
+                    self.pending = header[..8 + count].to_vec();
+                    self.state = State::Done;
+                    return Ok(());
+                }
+                header_len = 16;
+                Some(u64::from_be_bytes(header[8..16].try_into().unwrap()))
+            }
+            size => Some(size as u64),
+        };
+        let mut payload = size.map(|size| size.saturating_sub(header_len as u64));
+        let mut kept = KEPT_BOXES.contains(&&box_type);
+        let mut inner_type = None;
+        if &box_type == b"brob" {
+            let mut inner = [0u8; 4];
+            let count = self.read_full(&mut inner)?;
+            if count < 4 {
+                self.pending = [&header[..header_len], &inner[..count]].concat();
+                self.state = State::Done;
+                return Ok(());
+            }
+            kept = KEPT_BROB_BOXES.contains(&&inner);
+            payload = payload.map(|payload| payload.saturating_sub(4));
+            inner_type = Some(inner);
+        }
+
+        if kept {
+            self.pending = header[..header_len].to_vec();
+            self.pending.extend(inner_type.iter().flatten());
+            self.state = match payload {
+                Some(payload) => State::Payload(payload),
+                None => State::PassThrough,
+            };
+            return Ok(());
+        }
+
+        let Some(payload) = payload else {
+            log::trace!(
+                "BoxFilter: skipping {:?} to the end",
+                String::from_utf8_lossy(&box_type)
+            );
+            self.state = State::Done;
+            return Ok(());
+        };
+        let position = self.inner.stream_position()?;
+        let end = position.checked_add(payload);
+        if payload > i64::MAX as u64 || end.is_none_or(|end| self.len.is_some_and(|len| end > len))
+        {
+            // A truncated box, or a size that no stream has; nothing is after it anyway.
+            log::trace!(
+                "BoxFilter: {:?} of {} bytes runs past the end",
+                String::from_utf8_lossy(&box_type),
+                payload
+            );
+            self.state = State::Done;
+            return Ok(());
+        }
+        log::trace!(
+            "BoxFilter: skipping {:?}, {} bytes",
+            String::from_utf8_lossy(&box_type),
+            payload
+        );
+        self.inner.seek(SeekFrom::Current(payload as i64))?;
+        self.state = State::BoxHeader;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for BoxFilter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if self.pending_offset < self.pending.len() {
+                let pending = &self.pending[self.pending_offset..];
+                let count = pending.len().min(buf.len());
+                buf[..count].copy_from_slice(&pending[..count]);
+                self.pending_offset += count;
+                return Ok(count);
+            }
+            self.pending.clear();
+            self.pending_offset = 0;
+
+            match self.state {
+                State::Start => {
+                    let mut signature = [0u8; 2];
+                    let count = self.read_full(&mut signature)?;
+                    if signature[..count] == BARE_CODESTREAM_SIGNATURE {
+                        self.pending = signature.to_vec();
+                        self.state = State::PassThrough;
+                    } else {
+                        self.next_box(&signature[..count])?;
+                    }
+                }
+                State::BoxHeader => self.next_box(&[])?,
+                State::Payload(0) => self.state = State::BoxHeader,
+                State::Payload(left) => {
+                    let max = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+                    let count = self.inner.read(&mut buf[..max])?;
+                    if count == 0 {
+                        self.state = State::Done;
+                    } else {
+                        self.state = State::Payload(left - count as u64);
+                    }
+                    return Ok(count);
+                }
+                State::PassThrough => return self.inner.read(buf),
+                State::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Whether the container has a box of `box_type`, going by the box headers alone. The
+/// stream is left wherever the search stops.
+pub fn has_box(mut reader: impl Read + Seek, box_type: &[u8; 4]) -> std::io::Result<bool> {
+    reader.rewind()?;
+    let mut signature = [0u8; 12];
+    if reader.read_exact(&mut signature).is_err() || signature != CONTAINER_SIGNATURE {
+        return Ok(false);
+    }
+    loop {
+        let mut header = [0u8; 16];
+        if reader.read_exact(&mut header[..8]).is_err() {
+            return Ok(false);
+        }
+        if &header[4..8] == box_type {
+            return Ok(true);
+        }
+        let payload = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // The last box
+            0 => return Ok(false),
+            1 => {
+                if reader.read_exact(&mut header[8..16]).is_err() {
+                    return Ok(false);
+                }
+                u64::from_be_bytes(header[8..16].try_into().unwrap()).saturating_sub(16)
+            }
+            size => (size as u64).saturating_sub(8),
+        };
+        let Ok(payload) = i64::try_from(payload) else {
+            return Ok(false);
+        };
+        reader.seek(SeekFrom::Current(payload))?;
+    }
+}
+
+/// Appends a box, with a 64 bit size when it doesn't fit in 32 bits.
+fn push_box(out: &mut Vec<u8>, box_type: &[u8; 4], payload: &[u8]) {
+    match u32::try_from(payload.len() + 8) {
+        Ok(size) => {
+            out.extend_from_slice(&size.to_be_bytes());
+            out.extend_from_slice(box_type);
+        }
+        Err(_) => {
+            out.extend_from_slice(&1u32.to_be_bytes());
+            out.extend_from_slice(box_type);
+            out.extend_from_slice(&(payload.len() as u64 + 16).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+/// Returns the header length and the payload length of the box at the start of `data`, if
+/// the whole box is there.
+fn box_extent(data: &[u8]) -> Option<(usize, usize)> {
+    let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+    let (header_len, size) = match size {
+        0 => return (data.len() >= 8).then(|| (8, data.len() - 8)),
+        1 => (16, u64::from_be_bytes(data.get(8..16)?.try_into().ok()?)),
+        size => (8, size as u64),
+    };
+    let size = usize::try_from(size).ok()?;
+    (header_len <= size && size <= data.len()).then(|| (header_len, size - header_len))
+}
+
+/// Returns the file with `xml` in place of the first `xml ` box, or in a new box at the end.
+/// A `brob` box that compresses the XMP is replaced with a plain `xml ` box, any further XMP
+/// boxes are dropped, and a bare codestream is wrapped in a container first. Everything else
+/// is copied as is. A truncated
+/// or malformed container gives `None`, so that it isn't made worse.
+pub fn replace_xml(data: &[u8], xml: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() + xml.len() + CONTAINER_SIGNATURE.len() + 40);
+
+    if data.starts_with(&BARE_CODESTREAM_SIGNATURE) {
+        out.extend_from_slice(&CONTAINER_SIGNATURE);
+        out.extend_from_slice(&FILE_TYPE);
+        push_box(&mut out, b"jxlc", data);
+        push_box(&mut out, b"xml ", xml);
+        return Some(out);
+    }
+    if !data.starts_with(&CONTAINER_SIGNATURE) {
+        return None;
+    }
+
+    let mut rest = data;
+    let mut replaced = false;
+    while !rest.is_empty() {
+        let (header_len, payload_len) = box_extent(rest)?;
+        let box_type: &[u8; 4] = rest[4..8].try_into().unwrap();
+        let payload = &rest[header_len..header_len + payload_len];
+
+        let is_xml = box_type == b"xml " || (box_type == b"brob" && payload.starts_with(b"xml "));
+        if is_xml {
+            // Readers take the first one, so any later one is stale.
+            if !replaced {
+                push_box(&mut out, b"xml ", xml);
+                replaced = true;
+            }
+        } else if rest[..4] == [0, 0, 0, 0] {
+            // The box runs to the end of the file, which it no longer does if one is added.
+            push_box(&mut out, box_type, payload);
+        } else {
+            out.extend_from_slice(&rest[..header_len + payload_len]);
+        }
+        rest = &rest[header_len + payload_len..];
+    }
+
+    if !replaced {
+        push_box(&mut out, b"xml ", xml);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(boxes: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut data = CONTAINER_SIGNATURE.to_vec();
+        data.extend_from_slice(&FILE_TYPE);
+        for (box_type, payload) in boxes {
+            push_box(&mut data, box_type, payload);
+        }
+        data
+    }
+
+    #[test]
+    fn replace_xml_wraps_codestream() {
+        let codestream = [0xff, 0x0a, 1, 2, 3];
+        let out = replace_xml(&codestream, b"new").unwrap();
+        assert_eq!(out, container(&[(b"jxlc", &codestream), (b"xml ", b"new")]));
+    }
+
+    #[test]
+    fn replace_xml_replaces_boxes() {
+        let data = container(&[
+            (b"jxlc", &[0xff, 0x0a]),
+            (b"brob", b"xml compressed"),
+            (b"Exif", b"exif"),
+            (b"xml ", b"stale"),
+        ]);
+        let out = replace_xml(&data, b"new").unwrap();
+        assert_eq!(
+            out,
+            container(&[
+                (b"jxlc", &[0xff, 0x0a]),
+                (b"xml ", b"new"),
+                (b"Exif", b"exif"),
+            ])
+        );
+
+        // A brob box of something else is kept.
+        let data = container(&[(b"jxlc", &[0xff, 0x0a]), (b"brob", b"jumbcompressed")]);
+        let out = replace_xml(&data, b"new").unwrap();
+        assert_eq!(
+            out,
+            container(&[
+                (b"jxlc", &[0xff, 0x0a]),
+                (b"brob", b"jumbcompressed"),
+                (b"xml ", b"new"),
+            ])
+        );
+    }
+
+    #[test]
+    fn replace_xml_sizes_last_box() {
+        // The codestream box runs to the end of the file.
+        let mut data = container(&[]);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"jxlc");
+        data.extend_from_slice(&[0xff, 0x0a, 9]);
+
+        let out = replace_xml(&data, b"X").unwrap();
+        assert_eq!(
+            out,
+            container(&[(b"jxlc", &[0xff, 0x0a, 9]), (b"xml ", b"X")])
+        );
+    }
+
+    #[test]
+    fn replace_xml_rejects_malformed() {
+        let data = container(&[(b"jxlc", &[0xff, 0x0a, 1, 2])]);
+        assert!(
+            replace_xml(&data[..data.len() - 1], b"X").is_none(),
+            "truncated box"
+        );
+        assert!(
+            replace_xml(&data[..data.len() - 10], b"X").is_none(),
+            "truncated header"
+        );
+
+        let mut data = container(&[]);
+        data.extend_from_slice(&[0, 0, 0, 4]);
+        data.extend_from_slice(b"jxlc");
+        assert!(
+            replace_xml(&data, b"X").is_none(),
+            "size smaller than the header"
+        );
+
+        assert!(replace_xml(b"not an image", b"X").is_none(), "no signature");
+    }
+
+    #[test]
+    fn has_box_by_headers() {
+        let data = container(&[
+            (b"jxlc", &[0xff, 0x0a]),
+            (b"Exif", b"jbrd in a payload"),
+            (b"jbrd", b"reconstruction"),
+        ]);
+        let has = |data: &[u8], box_type| has_box(std::io::Cursor::new(data), box_type).unwrap();
+        assert!(has(&data, b"jbrd"));
+        assert!(!has(&data, b"xml "));
+        assert!(!has(&data[..data.len() - 22], b"jbrd"), "truncated");
+        assert!(!has(&[0xff, 0x0a, 1, 2], b"jbrd"), "a bare codestream");
+    }
+
+    fn filter(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        BoxFilter::new(std::io::Cursor::new(data), Some(data.len() as u64))
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn filter_skips_boxes() {
+        let data = container(&[
+            (b"jxlc", &[0xff, 0x0a, 1]),
+            (b"jbrd", b"reconstruction"),
+            (b"brob", b"jumbcompressed"),
+            (b"brob", b"Exifcompressed"),
+            (b"Exif", b"exif"),
+        ]);
+        assert_eq!(
+            filter(&data),
+            container(&[
+                (b"jxlc", &[0xff, 0x0a, 1]),
+                (b"brob", b"Exifcompressed"),
+                (b"Exif", b"exif"),
+            ])
+        );
+
+        let codestream = [0xff, 0x0a, 1, 2, 3];
+        assert_eq!(filter(&codestream), codestream, "a bare codestream");
+    }
+
+    #[test]
+    fn filter_unsized_last_box() {
+        let mut data = container(&[(b"jbrd", b"reconstruction")]);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"jxlc");
+        data.extend_from_slice(&[0xff, 0x0a, 1, 2]);
+        assert_eq!(
+            filter(&data),
+            data[..32]
+                .iter()
+                .chain(&data[54..])
+                .copied()
+                .collect::<Vec<_>>()
+        );
+
+        let mut data = container(&[(b"jxlc", &[0xff, 0x0a])]);
+        let expected = data.clone();
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"jumb");
+        data.extend_from_slice(b"to the end");
+        assert_eq!(filter(&data), expected);
+    }
+
+    #[test]
+    fn filter_large_size() {
+        let mut data = container(&[]);
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"jbrd");
+        data.extend_from_slice(&20u64.to_be_bytes());
+        data.extend_from_slice(b"skip");
+        let kept = data.len();
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"jxlc");
+        data.extend_from_slice(&18u64.to_be_bytes());
+        data.extend_from_slice(&[0xff, 0x0a]);
+
+        let mut expected = container(&[]);
+        expected.extend_from_slice(&data[kept..]);
+        assert_eq!(filter(&data), expected);
+    }
+
+    #[test]
+    fn filter_huge_size() {
+        let mut data = container(&[(b"jxlc", &[0xff, 0x0a])]);
+        let expected = data.clone();
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"jbrd");
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        data.extend_from_slice(b"skip");
+        assert_eq!(filter(&data), expected);
+
+        // Without a length to check against
+        let mut out = Vec::new();
+        BoxFilter::new(std::io::Cursor::new(&data), None)
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, expected, "unsized");
+    }
+
+    #[test]
+    fn filter_truncated() {
+        let data = container(&[(b"jxlc", &[0xff, 0x0a]), (b"Exif", b"exif")]);
+        // A partial header is passed on for jxl-oxide to judge.
+        let truncated = &data[..data.len() - 9];
+        assert_eq!(filter(truncated), truncated);
+        let truncated = &data[..data.len() - 2];
+        assert_eq!(filter(truncated), truncated, "a truncated kept box");
+
+        // A skipped box that runs past the end is not seeked over.
+        let data = container(&[(b"jxlc", &[0xff, 0x0a]), (b"jbrd", b"reconstruction")]);
+        assert_eq!(filter(&data[..data.len() - 2]), &data[..42]);
+    }
+}
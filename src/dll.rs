@@ -83,7 +83,11 @@ pub unsafe extern "system" fn DllRegisterServer() -> HRESULT {
 #[allow(non_snake_case)]
 #[doc(hidden)]
 pub unsafe extern "system" fn DllUnregisterServer() -> HRESULT {
-    if unregister().is_ok() {
+    let module_path = match get_module_path(unsafe { DLL_INSTANCE }) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    if unregister(&module_path).is_ok() {
         shell_change_notify();
         S_OK
     } else {
@@ -0,0 +1,476 @@
+// A small TIFF/EXIF reader for the `Exif` box.
+// https://www.cipa.jp/std/documents/e/DC-X008-Translation-2019-E.pdf
+
+use std::cell::Cell;
+
+pub const TAG_MAKE: u16 = 0x010f;
+pub const TAG_MODEL: u16 = 0x0110;
+pub const TAG_X_RESOLUTION: u16 = 0x011a;
+pub const TAG_Y_RESOLUTION: u16 = 0x011b;
+pub const TAG_RESOLUTION_UNIT: u16 = 0x0128;
+pub const TAG_EXIF_IFD: u16 = 0x8769;
+pub const TAG_GPS_IFD: u16 = 0x8825;
+
+// In the Exif IFD
+pub const TAG_EXPOSURE_TIME: u16 = 0x829a;
+pub const TAG_F_NUMBER: u16 = 0x829d;
+pub const TAG_ISO_SPEED: u16 = 0x8827;
+pub const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+pub const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+pub const TAG_EXPOSURE_BIAS: u16 = 0x9204;
+pub const TAG_METERING_MODE: u16 = 0x9207;
+pub const TAG_FLASH: u16 = 0x9209;
+pub const TAG_FOCAL_LENGTH: u16 = 0x920a;
+pub const TAG_FOCAL_LENGTH_IN_35MM_FILM: u16 = 0xa405;
+
+// In the GPS IFD
+pub const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+pub const TAG_GPS_LATITUDE: u16 = 0x0002;
+pub const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+pub const TAG_GPS_LONGITUDE: u16 = 0x0004;
+pub const TAG_GPS_ALTITUDE_REF: u16 = 0x0005;
+pub const TAG_GPS_ALTITUDE: u16 = 0x0006;
+
+// Limits the work done for malformed or hostile input.
+const MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Byte(Vec<u8>),
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<(u32, u32)>),
+    SByte(Vec<i8>),
+    Undefined(Vec<u8>),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    SRational(Vec<(i32, i32)>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+impl Value {
+    /// Returns the first value as an unsigned integer, for SHORT/LONG/BYTE tags.
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Value::Byte(v) => v.first().map(|&v| v as u32),
+            Value::Short(v) => v.first().map(|&v| v as u32),
+            Value::Long(v) => v.first().copied(),
+            _ => None,
+        }
+    }
+
+    /// Returns the n-th value as a floating point number, for numeric and rational tags.
+    pub fn get_f64(&self, index: usize) -> Option<f64> {
+        let rational = |num: f64, denom: f64| (denom != 0.0).then(|| num / denom);
+        match self {
+            Value::Byte(v) => v.get(index).map(|&v| v as f64),
+            Value::Short(v) => v.get(index).map(|&v| v as f64),
+            Value::Long(v) => v.get(index).map(|&v| v as f64),
+            Value::Rational(v) => v
+                .get(index)
+                .and_then(|&(num, denom)| rational(num as f64, denom as f64)),
+            Value::SByte(v) => v.get(index).map(|&v| v as f64),
+            Value::SShort(v) => v.get(index).map(|&v| v as f64),
+            Value::SLong(v) => v.get(index).map(|&v| v as f64),
+            Value::SRational(v) => v
+                .get(index)
+                .and_then(|&(num, denom)| rational(num as f64, denom as f64)),
+            Value::Float(v) => v.get(index).map(|&v| v as f64),
+            Value::Double(v) => v.get(index).copied(),
+            Value::Ascii(_) | Value::Undefined(_) => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        self.get_f64(0)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Ascii(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub tag: u16,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Ifd {
+    pub entries: Vec<Entry>,
+}
+
+impl Ifd {
+    pub fn get(&self, tag: u16) -> Option<&Value> {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| &entry.value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Exif {
+    /// The primary image IFD, a.k.a. `/app1/ifd` in WIC.
+    pub ifd0: Ifd,
+    /// The Exif private IFD, a.k.a. `/app1/ifd/exif` in WIC.
+    pub exif: Ifd,
+    /// The GPS IFD, a.k.a. `/app1/ifd/gps` in WIC.
+    pub gps: Ifd,
+}
+
+impl Exif {
+    /// Returns the horizontal and vertical resolution in DPI.
+    pub fn resolution(&self) -> Option<(f64, f64)> {
+        let x = self.ifd0.get(TAG_X_RESOLUTION).and_then(Value::as_f64);
+        let y = self.ifd0.get(TAG_Y_RESOLUTION).and_then(Value::as_f64);
+        let (x, y) = match (x, y) {
+            (Some(x), Some(y)) => (x, y),
+            (Some(r), None) | (None, Some(r)) => (r, r),
+            (None, None) => return None,
+        };
+
+        // Inches are the default unit. 1 means there's no absolute unit.
+        let unit = self.ifd0.get(TAG_RESOLUTION_UNIT).and_then(Value::as_u32);
+        let (x, y) = match unit.unwrap_or(2) {
+            2 => (x, y),
+            3 => (x * 2.54, y * 2.54),
+            _ => return None,
+        };
+
+        (x > 0.0 && y > 0.0).then_some((x, y))
+    }
+
+    /// Returns when the photo was taken, and the UTC offset in minutes if it was recorded.
+    pub fn date_taken(&self) -> Option<(DateTime, Option<i32>)> {
+        let date_time = self
+            .exif
+            .get(TAG_DATE_TIME_ORIGINAL)
+            .and_then(Value::as_str)
+            .and_then(DateTime::parse)?;
+        let offset = self
+            .exif
+            .get(TAG_OFFSET_TIME_ORIGINAL)
+            .and_then(Value::as_str)
+            .and_then(parse_offset);
+        Some((date_time, offset))
+    }
+
+    pub fn gps_latitude(&self) -> Option<GpsCoordinate> {
+        self.gps_coordinate(TAG_GPS_LATITUDE, TAG_GPS_LATITUDE_REF, ["N", "S"], 90.0)
+    }
+
+    pub fn gps_longitude(&self) -> Option<GpsCoordinate> {
+        self.gps_coordinate(TAG_GPS_LONGITUDE, TAG_GPS_LONGITUDE_REF, ["E", "W"], 180.0)
+    }
+
+    /// Returns the altitude in meters, and whether it is below the sea level.
+    pub fn gps_altitude(&self) -> Option<(f64, bool)> {
+        let altitude = self.gps.get(TAG_GPS_ALTITUDE).and_then(Value::as_f64)?;
+        if !altitude.is_finite() || altitude < 0.0 {
+            return None;
+        }
+        // Above the sea level if missing
+        let below = match self.gps.get(TAG_GPS_ALTITUDE_REF).and_then(Value::as_u32) {
+            None | Some(0) => false,
+            Some(1) => true,
+            Some(_) => return None,
+        };
+        Some((altitude, below))
+    }
+
+    /// Reads a coordinate, rejecting those out of range or without a valid reference.
+    /// Minutes and seconds may be left out, as some writers do.
+    fn gps_coordinate(
+        &self,
+        tag: u16,
+        ref_tag: u16,
+        references: [&'static str; 2],
+        max_degrees: f64,
+    ) -> Option<GpsCoordinate> {
+        let reference = self.gps.get(ref_tag).and_then(Value::as_str)?.trim();
+        let reference = references
+            .into_iter()
+            .find(|r| r.eq_ignore_ascii_case(reference))?;
+
+        let value = self.gps.get(tag)?;
+        let dms = [
+            value.get_f64(0)?,
+            value.get_f64(1).unwrap_or(0.0),
+            value.get_f64(2).unwrap_or(0.0),
+        ];
+        let [degrees, minutes, seconds] = dms;
+        let valid = dms.iter().all(|v| v.is_finite() && *v >= 0.0)
+            && minutes < 60.0
+            && seconds < 60.0
+            && degrees + minutes / 60.0 + seconds / 3600.0 <= max_degrees;
+        valid.then_some(GpsCoordinate { dms, reference })
+    }
+}
+
+/// A latitude or a longitude from the GPS IFD.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpsCoordinate {
+    /// Degrees, minutes and seconds
+    pub dms: [f64; 3],
+    /// "N" or "S" for latitudes, "E" or "W" for longitudes
+    pub reference: &'static str,
+}
+
+impl GpsCoordinate {
+    /// Returns the signed degrees, negative for the south and the west.
+    pub fn decimal(&self) -> f64 {
+        let [degrees, minutes, seconds] = self.dms;
+        let decimal = degrees + minutes / 60.0 + seconds / 3600.0;
+        match self.reference {
+            "S" | "W" => -decimal,
+            _ => decimal,
+        }
+    }
+}
+
+/// A date and time without a time zone, as EXIF records them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u16,
+    pub day: u16,
+    pub hour: u16,
+    pub minute: u16,
+    pub second: u16,
+}
+
+impl DateTime {
+    /// Parses "YYYY:MM:DD HH:MM:SS". Unknown dates are blanked out with spaces or zeros.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (date, time) = value.trim().split_once(' ')?;
+        let mut date = date.split(':').map(|v| v.parse::<u16>().ok());
+        let mut time = time.split(':').map(|v| v.parse::<u16>().ok());
+        let date_time = Self {
+            year: date.next()??,
+            month: date.next()??,
+            day: date.next()??,
+            hour: time.next()??,
+            minute: time.next()??,
+            second: time.next()??,
+        };
+        let valid = (1..=12).contains(&date_time.month)
+            && (1..=31).contains(&date_time.day)
+            && date_time.hour < 24
+            && date_time.minute < 60
+            && date_time.second < 60;
+        valid.then_some(date_time)
+    }
+}
+
+/// Parses an offset like "+09:00" into minutes.
+fn parse_offset(value: &str) -> Option<i32> {
+    let value = value.trim();
+    let (sign, value) = match value.as_bytes().first()? {
+        b'+' => (1, &value[1..]),
+        b'-' => (-1, &value[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = value.split_once(':')?;
+    Some(sign * (hours.parse::<i32>().ok()? * 60 + minutes.parse::<i32>().ok()?))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+    /// The bytes of the values read so far. Entries may all point at the same bytes, so
+    /// this is capped at the size of the data to keep a small payload from expanding.
+    decoded: Cell<usize>,
+}
+
+impl Reader<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.data.get(offset..offset.checked_add(len)?)
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.bytes(offset, 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.bytes(offset, 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        let bytes = self.bytes(offset, 8)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    fn i16(&self, offset: usize) -> Option<i16> {
+        self.u16(offset).map(|v| v as i16)
+    }
+
+    fn i32(&self, offset: usize) -> Option<i32> {
+        self.u32(offset).map(|v| v as i32)
+    }
+
+    fn f32(&self, offset: usize) -> Option<f32> {
+        self.u32(offset).map(f32::from_bits)
+    }
+
+    fn f64(&self, offset: usize) -> Option<f64> {
+        self.u64(offset).map(f64::from_bits)
+    }
+
+    fn rational(&self, offset: usize) -> Option<(u32, u32)> {
+        Some((self.u32(offset)?, self.u32(offset + 4)?))
+    }
+
+    fn srational(&self, offset: usize) -> Option<(i32, i32)> {
+        Some((self.i32(offset)?, self.i32(offset + 4)?))
+    }
+
+    fn values<T>(
+        &self,
+        offset: usize,
+        count: usize,
+        size: usize,
+        read: impl Fn(&Self, usize) -> Option<T>,
+    ) -> Option<Vec<T>> {
+        (0..count).map(|i| read(self, offset + i * size)).collect()
+    }
+
+    fn value(&self, entry_offset: usize) -> Option<(u16, Value)> {
+        let tag = self.u16(entry_offset)?;
+        let kind = self.u16(entry_offset + 2)?;
+        let count = self.u32(entry_offset + 4)? as usize;
+
+        let size = match kind {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => return None,
+        };
+        let total = count.checked_mul(size)?;
+        let offset = if total <= 4 {
+            entry_offset + 8
+        } else {
+            self.u32(entry_offset + 8)? as usize
+        };
+        // Fails early for counts that don't fit in the data.
+        let bytes = self.bytes(offset, total)?;
+        let decoded = self.decoded.get() + total;
+        if decoded > self.data.len() {
+            return None;
+        }
+        self.decoded.set(decoded);
+
+        let value = match kind {
+            1 => Value::Byte(bytes.to_vec()),
+            2 => {
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                Value::Ascii(
+                    String::from_utf8_lossy(&bytes[..end])
+                        .trim_end()
+                        .to_string(),
+                )
+            }
+            3 => Value::Short(self.values(offset, count, 2, Self::u16)?),
+            4 => Value::Long(self.values(offset, count, 4, Self::u32)?),
+            5 => Value::Rational(self.values(offset, count, 8, Self::rational)?),
+            6 => Value::SByte(bytes.iter().map(|&b| b as i8).collect()),
+            7 => Value::Undefined(bytes.to_vec()),
+            8 => Value::SShort(self.values(offset, count, 2, Self::i16)?),
+            9 => Value::SLong(self.values(offset, count, 4, Self::i32)?),
+            10 => Value::SRational(self.values(offset, count, 8, Self::srational)?),
+            11 => Value::Float(self.values(offset, count, 4, Self::f32)?),
+            _ => Value::Double(self.values(offset, count, 8, Self::f64)?),
+        };
+        Some((tag, value))
+    }
+
+    /// Reads an IFD, skipping entries that are malformed.
+    fn ifd(&self, offset: usize) -> Ifd {
+        let Some(count) = self.u16(offset) else {
+            return Ifd::default();
+        };
+        let entries = (0..(count as usize).min(MAX_ENTRIES))
+            .filter_map(|i| self.value(offset + 2 + i * 12))
+            .map(|(tag, value)| Entry { tag, value })
+            .collect();
+        Ifd { entries }
+    }
+
+    fn sub_ifd(&self, parent: &Ifd, tag: u16) -> Ifd {
+        match parent.get(tag).and_then(Value::as_u32) {
+            Some(offset) => self.ifd(offset as usize),
+            None => Ifd::default(),
+        }
+    }
+}
+
+/// Parses a TIFF structure that starts with the byte order mark.
+pub fn parse(data: &[u8]) -> Option<Exif> {
+    let big_endian = match data.get(0..4)? {
+        b"II*\0" => false,
+        b"MM\0*" => true,
+        _ => return None,
+    };
+    let reader = Reader {
+        data,
+        big_endian,
+        decoded: Cell::new(0),
+    };
+
+    let ifd0 = reader.ifd(reader.u32(4)? as usize);
+    let exif = reader.sub_ifd(&ifd0, TAG_EXIF_IFD);
+    let gps = reader.sub_ifd(&ifd0, TAG_GPS_IFD);
+
+    Some(Exif { ifd0, exif, gps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_values_are_bounded() {
+        // Every entry points at the same 1000 bytes.
+        let count = MAX_ENTRIES as u16;
+        let value_offset = 8 + 2 + count as u32 * 12 + 4;
+        let mut data = b"II*\0".to_vec();
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.extend_from_slice(&count.to_le_bytes());
+        for tag in 0..count {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&7u16.to_le_bytes());
+            data.extend_from_slice(&1000u32.to_le_bytes());
+            data.extend_from_slice(&value_offset.to_le_bytes());
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&[0; 1000]);
+
+        let exif = parse(&data).unwrap();
+        assert_eq!(exif.ifd0.entries.len(), data.len() / 1000);
+    }
+}
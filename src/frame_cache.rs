@@ -0,0 +1,94 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::FrameBuffer;
+
+/// Keeps the most recently rendered frames so that viewers asking for the same frames again,
+/// e.g. when looping an animation, don't render them again. Also used for the tiles of huge
+/// images, keyed by the frame index and the tile position.
+#[derive(Debug)]
+pub struct FrameCache<K = usize> {
+    capacity: usize,
+    /// Pairs of keys and frames, the most recently used at the back.
+    frames: VecDeque<(K, Rc<FrameBuffer>)>,
+}
+
+impl<K: PartialEq> FrameCache<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<Rc<FrameBuffer>> {
+        let position = self.frames.iter().position(|(k, _)| k == key)?;
+        let entry = self.frames.remove(position)?;
+        let frame = entry.1.clone();
+        self.frames.push_back(entry);
+        Some(frame)
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.frames.iter().any(|(k, _)| k == key)
+    }
+
+    pub fn insert(&mut self, key: K, frame: Rc<FrameBuffer>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.frames.retain(|(k, _)| *k != key);
+        while self.frames.len() >= self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((key, frame));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> Rc<FrameBuffer> {
+        Rc::new(FrameBuffer::new(2, 2, 4))
+    }
+
+    #[test]
+    fn reuses_cached_frames() {
+        let mut cache = FrameCache::new(2);
+        let first = frame();
+        cache.insert(0, first.clone());
+        cache.insert(1, frame());
+        let cached = cache.get(&0).expect("The cached frame");
+        assert!(Rc::ptr_eq(&cached, &first), "the same frame");
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = FrameCache::new(2);
+        cache.insert(0, frame());
+        cache.insert(1, frame());
+        // Frame 0 is used again, so frame 1 is the one to go.
+        cache.get(&0);
+        cache.insert(2, frame());
+        assert!(cache.contains(&0), "used recently");
+        assert!(!cache.contains(&1), "evicted");
+        assert!(cache.get(&1).is_none(), "to be rendered again");
+
+        // Rendering it again puts it back in place of the least recently used one.
+        cache.insert(1, frame());
+        assert!(cache.contains(&1), "cached again");
+        assert!(!cache.contains(&0), "evicted in turn");
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut cache = FrameCache::new(0);
+        cache.insert(0, frame());
+        assert!(cache.get(&0).is_none());
+    }
+}
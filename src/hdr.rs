@@ -0,0 +1,55 @@
+// HDR images are rendered as linear sRGB and handed to WIC as scRGB half floats, where 1.0
+// is 80 nits and values above it are kept instead of clipped.
+// https://learn.microsoft.com/en-us/windows/win32/direct3darticles/high-dynamic-range
+
+use half::f16;
+use jxl_oxide::JxlImage;
+use jxl_oxide::color::{ColourEncoding, TransferFunction};
+
+use crate::FrameBuffer;
+
+/// The luminance of scRGB 1.0 in nits.
+pub const SCRGB_WHITE_NITS: f32 = 80.0;
+
+/// Returns the peak luminance in nits if the image is HDR, i.e. it has a PQ or HLG transfer
+/// function, or is linear with a peak brighter than SDR white.
+pub fn intensity_target(image: &JxlImage) -> Option<f32> {
+    let metadata = &image.image_header().metadata;
+    let intensity_target = metadata.tone_mapping.intensity_target;
+    let ColourEncoding::Enum(encoding) = &metadata.colour_encoding else {
+        return None;
+    };
+    match encoding.tf {
+        TransferFunction::Pq | TransferFunction::Hlg => Some(intensity_target),
+        TransferFunction::Linear if intensity_target > 255.0 => Some(intensity_target),
+        _ => None,
+    }
+}
+
+/// Converts linear samples, where 1.0 is `intensity_target` nits, to scRGB RGBA half floats.
+/// The returned frame holds the bits of the half floats.
+pub fn to_scrgb(samples: &[f32], channels: usize, intensity_target: f32) -> FrameBuffer {
+    let scale = intensity_target / SCRGB_WHITE_NITS;
+    let half = |v: f32| f16::from_f32(v * scale).to_bits();
+    let one = f16::ONE.to_bits();
+
+    let buf = samples
+        .chunks_exact(channels)
+        .flat_map(|pixel| match *pixel {
+            [l] => [half(l), half(l), half(l), one],
+            [l, a] => [half(l), half(l), half(l), f16::from_f32(a).to_bits()],
+            [r, g, b] => [half(r), half(g), half(b), one],
+            [r, g, b, a, ..] => [half(r), half(g), half(b), f16::from_f32(a).to_bits()],
+            [] => unreachable!("chunks_exact never gives empty chunks"),
+        })
+        .collect();
+    FrameBuffer { channels: 4, buf }
+}
+
+pub fn half_to_f64(bits: u16) -> f64 {
+    f16::from_bits(bits).to_f64()
+}
+
+pub fn f64_to_half(value: f64) -> u16 {
+    f16::from_f64(value).to_bits()
+}
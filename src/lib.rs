@@ -5,12 +5,40 @@
 #![allow(unused_must_use)]
 #![allow(non_snake_case)]
 
-use jxl_oxide::{JxlImage, PixelFormat};
-use std::{cell::RefCell, io::BufReader, rc::Rc};
-use windows::core::{GUID, Interface, implement};
+use jxl_oxide::color::{EnumColourEncoding, RenderingIntent};
+use jxl_oxide::{CropInfo, JxlImage, PixelFormat};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::{cell::RefCell, rc::Rc};
+use windows::core::{GUID, HRESULT, IUnknown, Interface, implement, interface};
 
+mod bgra;
+mod budget;
+mod color;
+mod container;
+mod exif;
+mod frame_cache;
+mod hdr;
+mod limits;
+mod loader;
+mod mapping;
+mod metadata;
 mod registry;
+mod settings;
+mod thumbnail;
+mod tiles;
+mod tonemap;
+mod transform;
 mod winstream;
+use bgra::Output;
+use budget::Budget;
+use color::{ColorProfile, TargetColorSpace};
+use exif::Exif;
+use frame_cache::FrameCache;
+use loader::LoadedImage;
+use metadata::{JXLMetadataQueryReader, Metadata};
+use settings::Settings;
+use thumbnail::Downscaler;
 use winstream::WinStream;
 
 use windows as Windows;
@@ -24,14 +52,300 @@ mod dll;
 mod guid;
 
 mod properties;
+mod xmp;
+pub use properties::{JXLPropertyStore, PKEY_HAS_ALPHA};
 
 pub struct DecodedResult {
-    image: JxlImage,
+    /// jxl-oxide keeps the region to render in the image, so rendering a tile needs a mutable
+    /// borrow.
+    image: RefCell<JxlImage>,
     frame_count: usize,
     pixel_format: PixelFormat,
-    icc: Rc<Vec<u8>>,
+    color: ColorProfile,
+    metadata: Rc<Metadata>,
     width: u32,
     height: u32,
+    settings: Settings,
+    /// The peak luminance in nits for HDR images, whose frames are rendered as scRGB half
+    /// floats
+    hdr: Option<f32>,
+    bits_per_sample: u32,
+    /// The stream ended early, and the last frame is rendered from whatever passes were
+    /// loaded.
+    incomplete: bool,
+    frames: RefCell<FrameCache>,
+    /// Tiles of huge images, keyed by the frame index and the tile position
+    tiles: RefCell<FrameCache<(usize, (u32, u32))>>,
+    /// The side length of the tiles that frames larger than it are rendered in
+    tile_size: u32,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl DecodedResult {
+    fn render(&self, index: usize, region: Option<&WICRect>) -> windows::core::Result<FrameBuffer> {
+        let mut image = self.image.borrow_mut();
+        if let Some(region) = region {
+            image.set_image_region(CropInfo {
+                left: region.X as u32,
+                top: region.Y as u32,
+                width: region.Width as u32,
+                height: region.Height as u32,
+            });
+        }
+        let render = if self.is_loading_frame(index) {
+            // LF first, then whichever HF groups made it
+            image.render_loading_frame()
+        } else if region.is_some() {
+            image.render_frame_cropped(index)
+        } else {
+            image.render_frame(index)
+        };
+        if region.is_some() {
+            image.set_image_region(CropInfo {
+                left: 0,
+                top: 0,
+                width: self.width,
+                height: self.height,
+            });
+        }
+        let render = render.map_err(|err| {
+            windows::core::Error::new(WINCODEC_ERR_FRAMEMISSING, format!("{:?}", err))
+        })?;
+
+        let mut stream = render.stream();
+        let (width, height, channels) = (
+            stream.width() as usize,
+            stream.height() as usize,
+            stream.channels() as usize,
+        );
+
+        if let Some(intensity_target) = self.hdr {
+            let mut samples = vec![0f32; width * height * channels];
+            stream.write_to_buffer(&mut samples[..]);
+            return Ok(hdr::to_scrgb(&samples, channels, intensity_target));
+        }
+
+        let mut fb = FrameBuffer::new(width, height, channels);
+        stream.write_to_buffer(&mut fb.buf[..]);
+
+        if matches!(self.pixel_format, PixelFormat::Graya) {
+            fb = fb.gray_alpha_to_rgba();
+        }
+        Ok(fb)
+    }
+
+    /// Whether the frame is the partially loaded one of an incomplete image.
+    fn is_loading_frame(&self, index: usize) -> bool {
+        self.incomplete && index + 1 == self.frame_count
+    }
+
+    /// Returns the rendered frame, from the cache if it has been rendered recently. Huge
+    /// frames are rendered in tiles, so that the budget is checked between them.
+    fn frame(&self, index: usize, budget: &Budget) -> windows::core::Result<Rc<FrameBuffer>> {
+        if let Some(frame) = self.frames.borrow_mut().get(&index) {
+            log::trace!("DecodedResult::frame {}: cached", index);
+            return Ok(frame);
+        }
+        log::trace!("DecodedResult::frame {}: rendering", index);
+        budget.check()?;
+        self.settings
+            .limits
+            .check_memory(self.frame_bytes(self.width, self.height))?;
+
+        let frame = if self.renders_in_tiles(index) {
+            let full = self.full_rect();
+            let mut frame = FrameBuffer::new(
+                self.width as usize,
+                self.height as usize,
+                self.frame_channels(),
+            );
+            self.render_tiles(index, &full, budget, false, |tile, tile_rect| {
+                tiles::paste(tile, tile_rect, &mut frame, &full)
+            })?;
+            frame
+        } else {
+            self.render(index, None)?
+        };
+        let frame = Rc::new(frame);
+        self.frames.borrow_mut().insert(index, frame.clone());
+        Ok(frame)
+    }
+
+    /// Whether the frame is rendered in tiles. The partially loaded frame is rendered from
+    /// whatever passes there are, which jxl-oxide does only for the whole frame.
+    fn renders_in_tiles(&self, index: usize) -> bool {
+        tiles::is_tiled(self.width, self.height, self.tile_size) && !self.is_loading_frame(index)
+    }
+
+    fn full_rect(&self) -> WICRect {
+        WICRect {
+            X: 0,
+            Y: 0,
+            Width: self.width as i32,
+            Height: self.height as i32,
+        }
+    }
+
+    /// Whether `rect` should be rendered from tiles instead of the whole frame. A rectangle
+    /// that covers most of the frame renders the whole of it instead, as long as the frame
+    /// can be cached for the next rectangles.
+    fn prefers_tiles(&self, index: usize, rect: &WICRect) -> bool {
+        let frames = self.frames.borrow();
+        if !self.renders_in_tiles(index) || frames.contains(&index) {
+            return false;
+        }
+        let tile_count = tiles::tiles_along(self.width, self.tile_size)
+            * tiles::tiles_along(self.height, self.tile_size);
+        frames.capacity() == 0
+            || tiles::tiles_in(rect, self.width, self.height, self.tile_size).len() * 2 < tile_count
+    }
+
+    /// Hands each tile that intersects with `rect` to `add`, rendering those that are not
+    /// cached and checking the budget before each of them. The rendered tiles are cached if
+    /// `cache_tiles`.
+    fn render_tiles(
+        &self,
+        index: usize,
+        rect: &WICRect,
+        budget: &Budget,
+        cache_tiles: bool,
+        mut add: impl FnMut(&FrameBuffer, &WICRect),
+    ) -> windows::core::Result<()> {
+        for (position, tile_rect) in tiles::tiles_in(rect, self.width, self.height, self.tile_size)
+        {
+            let key = (index, position);
+            let cached = self.tiles.borrow_mut().get(&key);
+            let tile = match cached {
+                Some(tile) => tile,
+                None => {
+                    budget.check()?;
+                    log::trace!("DecodedResult::render_tiles {}: {:?}", index, tile_rect);
+                    let tile = Rc::new(self.render(index, Some(&tile_rect))?);
+                    if cache_tiles {
+                        self.tiles.borrow_mut().insert(key, tile.clone());
+                    }
+                    tile
+                }
+            };
+            add(&tile, &tile_rect);
+        }
+        Ok(())
+    }
+
+    /// Renders only the tiles that intersect with `rect`, reusing the tiles rendered for
+    /// earlier rectangles. The returned frame covers `rect`.
+    fn region(
+        &self,
+        index: usize,
+        rect: &WICRect,
+        budget: &Budget,
+    ) -> windows::core::Result<FrameBuffer> {
+        let mut dst = FrameBuffer::new(
+            rect.Width as usize,
+            rect.Height as usize,
+            self.frame_channels(),
+        );
+        self.render_tiles(index, rect, budget, true, |tile, tile_rect| {
+            tiles::paste(tile, tile_rect, &mut dst, rect)
+        })?;
+        Ok(dst)
+    }
+
+    /// Renders `rect` of the frame scaled down to `width` x `height`. Huge frames are
+    /// rendered and averaged one tile at a time. jxl-oxide can't decode at a lower
+    /// resolution, so this saves memory and the tiles outside of `rect`, but not the decoding
+    /// of the tiles inside. What is rendered is cached if `cache`, for callers that copy the
+    /// scaled frame in bands, but not for a one-off thumbnail.
+    fn scaled(
+        &self,
+        index: usize,
+        width: u32,
+        height: u32,
+        rect: &WICRect,
+        budget: &Budget,
+        cache: bool,
+    ) -> windows::core::Result<FrameBuffer> {
+        let mut downscaler = Downscaler::new(
+            self.width,
+            self.height,
+            width,
+            height,
+            rect,
+            self.frame_channels(),
+            self.hdr.is_some(),
+        );
+        let full = self.full_rect();
+        let cached = self.frames.borrow_mut().get(&index);
+        if let Some(frame) = cached {
+            downscaler.add(&frame, &full);
+            return Ok(downscaler.finish());
+        }
+        if cache && !self.renders_in_tiles(index) {
+            downscaler.add(&self.frame(index, budget)?, &full);
+            return Ok(downscaler.finish());
+        }
+        if !self.renders_in_tiles(index) {
+            budget.check()?;
+            self.settings
+                .limits
+                .check_memory(self.frame_bytes(self.width, self.height))?;
+            downscaler.add(&self.render(index, None)?, &full);
+            return Ok(downscaler.finish());
+        }
+
+        let source = downscaler.source_rect();
+        self.render_tiles(index, &source, budget, cache, |tile, tile_rect| {
+            downscaler.add(tile, tile_rect)
+        })?;
+        Ok(downscaler.finish())
+    }
+
+    /// Starts the budget of one call that renders, shared by everything rendered in it, as a
+    /// decoder may be kept open for long.
+    fn budget(&self) -> Budget {
+        Budget::new(self.settings.decode_timeout, self.cancelled.clone())
+    }
+
+    /// The bytes of a rendered `width` by `height` frame. HDR frames need their float
+    /// samples on top of that while they are converted.
+    fn frame_bytes(&self, width: u32, height: u32) -> u64 {
+        let pixels = width as u64 * height as u64;
+        let bytes = pixels * self.frame_channels() as u64 * std::mem::size_of::<u16>() as u64;
+        match self.hdr {
+            Some(_) => bytes + pixels * 4 * std::mem::size_of::<f32>() as u64,
+            None => bytes,
+        }
+    }
+
+    /// Fits the frame caches in the memory limit, along with the frame being rendered.
+    fn size_caches(&mut self) {
+        let limits = &self.settings.limits;
+        let frame_bytes = self.frame_bytes(self.width, self.height);
+        let tile_bytes = self.frame_bytes(self.tile_size, self.tile_size);
+        self.frames = RefCell::new(FrameCache::new(
+            limits.cache_capacity(self.settings.frame_cache_size as usize, frame_bytes),
+        ));
+        // Row strips of a wide image go through a whole row of tiles before coming back to
+        // the first tile, and a strip may straddle two rows.
+        let tile_count =
+            tiles::TILE_CACHE_SIZE.max(2 * tiles::tiles_along(self.width, self.tile_size));
+        self.tiles = RefCell::new(FrameCache::new(
+            limits.cache_capacity(tile_count, tile_bytes),
+        ));
+    }
+
+    /// The number of channels in rendered frames, after expanding gray alpha to RGBA.
+    fn frame_channels(&self) -> usize {
+        if self.hdr.is_some() {
+            return 4;
+        }
+        match self.pixel_format {
+            PixelFormat::Gray => 1,
+            PixelFormat::Rgb => 3,
+            PixelFormat::Graya | PixelFormat::Rgba | PixelFormat::Cmyk => 4,
+            PixelFormat::Cmyka => 5,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -47,17 +361,183 @@ impl FrameBuffer {
             buf: vec![0u16; width * height * channels],
         }
     }
+
+    /// WIC has no gray alpha format, so expand it to RGBA.
+    fn gray_alpha_to_rgba(&self) -> Self {
+        debug_assert_eq!(self.channels, 2);
+        let buf = self
+            .buf
+            .chunks_exact(2)
+            .flat_map(|pixel| [pixel[0], pixel[0], pixel[0], pixel[1]])
+            .collect();
+        Self { channels: 4, buf }
+    }
+
+    /// Writes the given rectangle of the frame into a caller buffer whose rows are `stride`
+    /// bytes apart. The rectangle must be inside the frame.
+    fn write_rect(
+        &self,
+        width: usize,
+        rect: &WICRect,
+        stride: usize,
+        dst: &mut [u8],
+        output: Output,
+    ) -> windows::core::Result<()> {
+        let row_len = rect.Width as usize * self.channels;
+        let rows = rect.Height as usize;
+        if row_len == 0 || rows == 0 {
+            return Ok(());
+        }
+        let row_bytes = rect.Width as usize * output.bytes_per_pixel(self.channels);
+
+        if stride < row_bytes {
+            return Err(windows::core::Error::new(
+                E_INVALIDARG,
+                "Stride is smaller than the row size",
+            ));
+        }
+        let required = stride
+            .checked_mul(rows - 1)
+            .and_then(|size| size.checked_add(row_bytes));
+        if required.is_none_or(|required| dst.len() < required) {
+            return Err(WINCODEC_ERR_INSUFFICIENTBUFFER.into());
+        }
+
+        for y in 0..rows {
+            let src_offset = ((rect.Y as usize + y) * width + rect.X as usize) * self.channels;
+            let row = &self.buf[src_offset..src_offset + row_len];
+            let dst_row = &mut dst[y * stride..y * stride + row_bytes];
+            match output {
+                Output::Native => {
+                    let row =
+                        unsafe { std::slice::from_raw_parts(row.as_ptr() as *const u8, row_bytes) };
+                    dst_row.copy_from_slice(row);
+                }
+                Output::Bgra {
+                    premultiplied,
+                    dither,
+                } => bgra::convert_row(
+                    row,
+                    self.channels,
+                    dst_row,
+                    rect.X as usize,
+                    rect.Y as usize + y,
+                    premultiplied,
+                    dither,
+                ),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Lets a host stop a decoder that it no longer needs, e.g. a viewer moving on to the next
+/// image, and bound how long decoding may take. The decoder answers QueryInterface for it.
+///
+/// There is no proxy for this interface and the decoder doesn't use the free threaded
+/// marshaler, so it can't be marshaled to another apartment. A host that decodes in the
+/// multithreaded apartment can call it from any other thread of that apartment. One that
+/// decodes in a single-threaded apartment can only set a timeout, or cancel between calls.
+#[interface("2f0a1e64-8d3b-4c57-9a26-7b5e1c4d9f80")]
+pub unsafe trait IJXLDecodeControl: IUnknown {
+    /// Makes the decoder fail with E_ABORT from now on. Loading stops at the next chunk and
+    /// rendering at the next tile, but a frame that fits in one tile, which includes most
+    /// thumbnails, renders in one call to jxl-oxide that can't be interrupted.
+    unsafe fn Cancel(&self) -> HRESULT;
+    /// Overrides the DecodeTimeout setting in milliseconds for the next Initialize and the
+    /// renders after it. 0 has no limit.
+    unsafe fn SetDecodeTimeout(&self, timeout_ms: u32) -> HRESULT;
 }
 
-#[implement(Windows::Win32::Graphics::Imaging::IWICBitmapDecoder)]
+#[implement(
+    Windows::Win32::Graphics::Imaging::IWICBitmapDecoder,
+    IJXLDecodeControl
+)]
 #[derive(Default)]
 pub struct JXLWICBitmapDecoder {
-    decoded: RefCell<Option<DecodedResult>>,
+    decoded: RefCell<Option<Rc<DecodedResult>>>,
+    cancelled: Arc<AtomicBool>,
+    /// Set through IJXLDecodeControl::SetDecodeTimeout, plus one so that 0 is unset
+    decode_timeout: AtomicU64,
+    /// Overrides the ColorSpace setting
+    color_space: Option<TargetColorSpace>,
+    /// Overrides tiles::TILE_SIZE
+    tile_size: Option<u32>,
 }
 
 impl JXLWICBitmapDecoder {
     pub const CLSID: GUID = GUID::from_u128(0x655896c6_b7d0_4d74_8afb_a02ece3f5e5a);
     pub const CONTAINER_ID: GUID = GUID::from_u128(0x81e337bc_c1d1_4dee_a17c_402041ba9b5e);
+
+    /// Every format that frames can be copied in.
+    pub const PIXEL_FORMATS: &[GUID] = &[
+        GUID_WICPixelFormat16bppGray,
+        GUID_WICPixelFormat48bppRGB,
+        GUID_WICPixelFormat64bppRGBA,
+        GUID_WICPixelFormat64bppCMYK,
+        GUID_WICPixelFormat80bppCMYKAlpha,
+        GUID_WICPixelFormat64bppRGBAHalf,
+        GUID_WICPixelFormat32bppBGRA,
+        GUID_WICPixelFormat32bppPBGRA,
+    ];
+
+    /// Formats that can hold HDR values. Anything else gets tone mapped HDR frames.
+    const FLOAT_PIXEL_FORMATS: &[GUID] = &[
+        GUID_WICPixelFormat16bppGrayHalf,
+        GUID_WICPixelFormat32bppGrayFloat,
+        GUID_WICPixelFormat48bppRGBHalf,
+        GUID_WICPixelFormat64bppRGBHalf,
+        GUID_WICPixelFormat64bppRGBAHalf,
+        GUID_WICPixelFormat96bppRGBFloat,
+        GUID_WICPixelFormat128bppRGBFloat,
+        GUID_WICPixelFormat128bppRGBAFloat,
+        GUID_WICPixelFormat128bppPRGBAFloat,
+    ];
+
+    /// Creates a decoder that gives up with E_ABORT once `cancelled` is set, e.g. when the
+    /// host no longer needs the image.
+    pub fn with_cancel_flag(cancelled: Arc<AtomicBool>) -> Self {
+        Self {
+            cancelled,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a decoder that renders SDR images in the named color space, which is one of
+    /// the values of the ColorSpace setting, instead of the one the setting picks. None if
+    /// the name is unknown.
+    pub fn with_color_space(name: &str) -> Option<Self> {
+        Some(Self {
+            color_space: Some(TargetColorSpace::from_name(name)?),
+            ..Default::default()
+        })
+    }
+
+    /// Creates a decoder that renders frames larger than `tile_size` in tiles of that size,
+    /// so that the tiled rendering can be tested without a huge image.
+    #[doc(hidden)]
+    pub fn with_tile_size(tile_size: u32) -> Self {
+        Self {
+            tile_size: Some(tile_size.max(1)),
+            ..Default::default()
+        }
+    }
+}
+
+impl IJXLDecodeControl_Impl for JXLWICBitmapDecoder_Impl {
+    unsafe fn Cancel(&self) -> HRESULT {
+        log::trace!("JXLWICBitmapDecoder::Cancel");
+        self.cancelled.store(true, Ordering::Relaxed);
+        S_OK
+    }
+
+    unsafe fn SetDecodeTimeout(&self, timeout_ms: u32) -> HRESULT {
+        log::trace!("JXLWICBitmapDecoder::SetDecodeTimeout {}", timeout_ms);
+        self.decode_timeout
+            .store(timeout_ms as u64 + 1, Ordering::Relaxed);
+        S_OK
+    }
 }
 
 impl IWICBitmapDecoder_Impl for JXLWICBitmapDecoder_Impl {
@@ -74,12 +554,19 @@ impl IWICBitmapDecoder_Impl for JXLWICBitmapDecoder_Impl {
     ) -> windows::core::Result<()> {
         log::trace!("JXLWICBitmapDecoder::Initialize");
 
+        let mut settings = Settings::load();
+        if let Some(timeout) = self.decode_timeout.load(Ordering::Relaxed).checked_sub(1) {
+            settings.decode_timeout = timeout as u32;
+        }
+        if let Some(color_space) = self.color_space {
+            settings.color_space = color_space;
+        }
+        let budget = Budget::new(settings.decode_timeout, self.cancelled.clone());
         let stream = WinStream::from(pistream.unwrap());
-        let reader = BufReader::new(stream);
-
-        let image = JxlImage::builder().read(reader).map_err(|err| {
-            windows::core::Error::new(WINCODEC_ERR_BADIMAGE, format!("{:?}", err))
-        })?;
+        let LoadedImage {
+            mut image,
+            incomplete,
+        } = loader::read_stream(stream, &budget, &settings.limits)?;
 
         let (width, height, _left, _top) = image.image_header().metadata.apply_orientation(
             image.image_header().size.width,
@@ -89,14 +576,47 @@ impl IWICBitmapDecoder_Impl for JXLWICBitmapDecoder_Impl {
             false,
         );
 
-        self.decoded.replace(Some(DecodedResult {
-            frame_count: image.num_loaded_keyframes(),
+        let hdr = hdr::intensity_target(&image);
+        let target = if let Some(intensity_target) = hdr {
+            log::trace!(
+                "JXLWICBitmapDecoder::Initialize: HDR, {} nits",
+                intensity_target
+            );
+            // Linear output keeps the highlights that an integer format would clip. scRGB has
+            // the sRGB primaries but can still hold wider gamuts, so the target color space
+            // doesn't apply.
+            image
+                .request_color_encoding(EnumColourEncoding::srgb_linear(RenderingIntent::Relative));
+            TargetColorSpace::Original
+        } else if color::request_rgb_gray(&mut image) {
+            TargetColorSpace::Srgb
+        } else {
+            settings.color_space.request(&mut image)
+        };
+
+        // The partially loaded keyframe comes after the complete ones.
+        let frame_count = image.num_loaded_keyframes() + incomplete as usize;
+        settings.limits.check_frames(frame_count)?;
+
+        let mut decoded = DecodedResult {
+            frame_count,
             pixel_format: image.pixel_format(),
-            icc: Rc::new(image.rendered_icc()),
-            image,
+            color: ColorProfile::from_image(&image, hdr.is_some(), target),
+            metadata: Rc::new(Metadata::from_image(&image)),
             width,
             height,
-        }));
+            settings,
+            hdr,
+            bits_per_sample: image.image_header().metadata.bit_depth.bits_per_sample(),
+            incomplete,
+            image: RefCell::new(image),
+            frames: RefCell::new(FrameCache::new(0)),
+            tiles: RefCell::new(FrameCache::new(0)),
+            tile_size: self.tile_size.unwrap_or(tiles::TILE_SIZE),
+            cancelled: self.cancelled.clone(),
+        };
+        decoded.size_caches();
+        self.decoded.replace(Some(Rc::new(decoded)));
 
         Ok(())
     }
@@ -125,7 +645,18 @@ impl IWICBitmapDecoder_Impl for JXLWICBitmapDecoder_Impl {
 
     fn GetMetadataQueryReader(&self) -> windows::core::Result<IWICMetadataQueryReader> {
         log::trace!("JXLWICBitmapDecoder::GetMetadataQueryReader");
-        Err(WINCODEC_ERR_UNSUPPORTEDOPERATION.into())
+        let decoded_ref = self.decoded.borrow();
+        let Some(decoded) = decoded_ref.as_ref() else {
+            return Err(WINCODEC_ERR_NOTINITIALIZED.into());
+        };
+
+        Ok(
+            JXLMetadataQueryReader::new(
+                decoded.metadata.clone(),
+                JXLWICBitmapDecoder::CONTAINER_ID,
+            )
+            .into(),
+        )
     }
 
     fn GetPreview(&self) -> windows::core::Result<IWICBitmapSource> {
@@ -151,25 +682,20 @@ impl IWICBitmapDecoder_Impl for JXLWICBitmapDecoder_Impl {
             ppicolorcontexts,
             pcactualcount
         );
-        // TODO: Proper color context
-        unsafe {
-            if let Some(context) = ppicolorcontexts.as_mut()
-                && ccount == 1 {
-                    context
-                        .as_mut()
-                        .expect("There should be a color context here")
-                        .InitializeFromMemory(&decoded.icc[..])?;
-                }
-            if !pcactualcount.is_null() {
-                *pcactualcount = 1;
-            }
-        }
-        Ok(())
+        color::get_color_contexts(&decoded.color, ccount, ppicolorcontexts, pcactualcount)
     }
 
     fn GetThumbnail(&self) -> windows::core::Result<IWICBitmapSource> {
         log::trace!("JXLWICBitmapDecoder::GetThumbnail");
-        Err(WINCODEC_ERR_CODECNOTHUMBNAIL.into())
+        let decoded_ref = self.decoded.borrow();
+        let Some(decoded) = decoded_ref.as_ref() else {
+            return Err(WINCODEC_ERR_NOTINITIALIZED.into());
+        };
+
+        let thumbnail: IWICBitmapFrameDecode = JXLWICBitmapFrameDecode::new(decoded.clone(), 0)
+            .thumbnail(&decoded.budget())?
+            .into();
+        thumbnail.cast()
     }
 
     fn GetFrameCount(&self) -> windows::core::Result<u32> {
@@ -184,64 +710,223 @@ impl IWICBitmapDecoder_Impl for JXLWICBitmapDecoder_Impl {
     }
 
     fn GetFrame(&self, index: u32) -> windows::core::Result<IWICBitmapFrameDecode> {
-        let mut decoded_ref = self.decoded.borrow_mut();
-        let Some(decoded) = decoded_ref.as_mut() else {
+        let decoded_ref = self.decoded.borrow();
+        let Some(decoded) = decoded_ref.as_ref() else {
             return Err(WINCODEC_ERR_NOTINITIALIZED.into());
         };
 
         log::trace!("[{}/{}]", index, decoded.frame_count);
-
-        if index >= decoded.frame_count as u32 {
+        if index as usize >= decoded.frame_count {
             return Err(WINCODEC_ERR_FRAMEMISSING.into());
         }
+        let bytes = if tiles::is_tiled(decoded.width, decoded.height, decoded.tile_size) {
+            // Huge frames can still be copied a tile at a time.
+            decoded.frame_bytes(decoded.tile_size, decoded.tile_size)
+        } else {
+            decoded.frame_bytes(decoded.width, decoded.height)
+        };
+        decoded.settings.limits.check_memory(bytes)?;
 
-        let render = decoded.image.render_frame(index as usize).map_err(|err| {
-            windows::core::Error::new(WINCODEC_ERR_FRAMEMISSING, format!("{:?}", err))
-        })?;
-
-        let mut stream = render.stream();
-        let mut fb = FrameBuffer::new(
-            stream.width() as usize,
-            stream.height() as usize,
-            stream.channels() as usize,
-        );
-        stream.write_to_buffer(&mut fb.buf[..]);
-
-        let frame_decode = JXLWICBitmapFrameDecode::new(
-            fb,
-            decoded.pixel_format,
-            decoded.icc.clone(),
-            decoded.width,
-            decoded.height,
-        );
-        Ok(frame_decode.into())
+        // Rendering is deferred until the pixels are requested.
+        Ok(JXLWICBitmapFrameDecode::new(decoded.clone(), index as usize).into())
     }
 }
 
-#[implement(Windows::Win32::Graphics::Imaging::IWICBitmapFrameDecode)]
+#[implement(
+    Windows::Win32::Graphics::Imaging::IWICBitmapFrameDecode,
+    Windows::Win32::Graphics::Imaging::IWICBitmapSourceTransform
+)]
 pub struct JXLWICBitmapFrameDecode {
-    frame: FrameBuffer,
-    pixel_format: PixelFormat,
-    icc: Rc<Vec<u8>>,
+    decoded: Rc<DecodedResult>,
+    index: usize,
     width: u32,
     height: u32,
+    /// Pixels that are already scaled down, for thumbnails. Full size frames are rendered
+    /// through the decoder cache when the pixels are first requested.
+    scaled: Option<Rc<FrameBuffer>>,
+    /// Whether the HDR pixels in `scaled` are already tone mapped to SDR
+    tone_mapped: bool,
 }
 
 impl JXLWICBitmapFrameDecode {
-    pub fn new(
-        frame: FrameBuffer,
-        pixel_format: PixelFormat,
-        icc: Rc<Vec<u8>>,
+    pub fn new(decoded: Rc<DecodedResult>, index: usize) -> Self {
+        Self {
+            width: decoded.width,
+            height: decoded.height,
+            decoded,
+            index,
+            scaled: None,
+            tone_mapped: false,
+        }
+    }
+
+    /// Whether the pixels are scRGB half floats.
+    fn is_hdr(&self) -> bool {
+        self.decoded.hdr.is_some() && !self.tone_mapped
+    }
+
+    /// Whether 8 bit BGRA keeps all the precision, so that it can be the native format.
+    fn is_8bit(&self) -> bool {
+        !self.is_hdr()
+            && !matches!(
+                self.decoded.pixel_format,
+                PixelFormat::Cmyk | PixelFormat::Cmyka
+            )
+            && (self.tone_mapped || self.decoded.bits_per_sample <= 8)
+    }
+
+    /// The 16 bit integer format that matches the frame buffer.
+    fn wide_pixel_format(&self) -> GUID {
+        if self.tone_mapped {
+            return GUID_WICPixelFormat64bppRGBA;
+        }
+        match self.decoded.pixel_format {
+            PixelFormat::Gray => GUID_WICPixelFormat16bppGray,
+            PixelFormat::Rgb => GUID_WICPixelFormat48bppRGB,
+            // WIC doesn't support Graya, so the frame buffer is expanded to RGBA
+            PixelFormat::Graya | PixelFormat::Rgba => GUID_WICPixelFormat64bppRGBA,
+            PixelFormat::Cmyk => GUID_WICPixelFormat64bppCMYK,
+            PixelFormat::Cmyka => GUID_WICPixelFormat80bppCMYKAlpha,
+        }
+    }
+
+    /// Returns how to write the pixels in the given format, and whether HDR pixels need to
+    /// be tone mapped for it. None if the format is not supported.
+    fn output(&self, format: &GUID) -> Option<(bool, Output)> {
+        let bgra = |premultiplied| Output::Bgra {
+            premultiplied,
+            dither: self.decoded.settings.dither,
+        };
+        let tone_map = self.is_hdr();
+        if tone_map && *format == GUID_WICPixelFormat64bppRGBAHalf {
+            Some((false, Output::Native))
+        } else if tone_map && *format == GUID_WICPixelFormat64bppRGBA {
+            Some((true, Output::Native))
+        } else if (tone_map || self.is_8bit()) && *format == GUID_WICPixelFormat32bppBGRA {
+            Some((tone_map, bgra(false)))
+        } else if (tone_map || self.is_8bit()) && *format == GUID_WICPixelFormat32bppPBGRA {
+            Some((tone_map, bgra(true)))
+        } else if !tone_map && *format == self.wide_pixel_format() {
+            Some((false, Output::Native))
+        } else {
+            None
+        }
+    }
+
+    fn tone_map(&self, frame: &FrameBuffer) -> FrameBuffer {
+        let peak = self.decoded.hdr.unwrap_or(tonemap::REFERENCE_WHITE_NITS);
+        log::trace!(
+            "JXLWICBitmapFrameDecode::tone_map {} nits, {:?}",
+            peak,
+            self.decoded.settings.tone_mapping
+        );
+        tonemap::tone_map(frame, peak, self.decoded.settings.tone_mapping)
+    }
+
+    fn frame(&self, budget: &Budget) -> windows::core::Result<Rc<FrameBuffer>> {
+        match &self.scaled {
+            Some(frame) => Ok(frame.clone()),
+            None => self.decoded.frame(self.index, budget),
+        }
+    }
+
+    /// Renders `rect` of the frame scaled down to `width` x `height`, caching the rendered
+    /// frame or tiles if `cache`.
+    fn scaled(
+        &self,
         width: u32,
         height: u32,
-    ) -> Self {
-        Self {
-            frame,
-            pixel_format,
-            icc,
+        rect: &WICRect,
+        budget: &Budget,
+        cache: bool,
+    ) -> windows::core::Result<FrameBuffer> {
+        let Some(frame) = &self.scaled else {
+            return self
+                .decoded
+                .scaled(self.index, width, height, rect, budget, cache);
+        };
+        let mut downscaler = Downscaler::new(
+            self.width,
+            self.height,
             width,
             height,
+            rect,
+            frame.channels,
+            self.is_hdr(),
+        );
+        let full = WICRect {
+            X: 0,
+            Y: 0,
+            Width: self.width as i32,
+            Height: self.height as i32,
+        };
+        downscaler.add(frame, &full);
+        Ok(downscaler.finish())
+    }
+
+    /// Writes the full size pixels in `rect`. Small rectangles of huge images are rendered
+    /// from tiles so that panning in a zoomed viewer doesn't render the whole frame.
+    fn copy_rect(
+        &self,
+        rect: &WICRect,
+        stride: usize,
+        dst: &mut [u8],
+        output: Output,
+        budget: &Budget,
+    ) -> windows::core::Result<()> {
+        if self.scaled.is_none() && self.decoded.prefers_tiles(self.index, rect) {
+            let region = self.decoded.region(self.index, rect, budget)?;
+            let full = WICRect {
+                X: 0,
+                Y: 0,
+                ..*rect
+            };
+            return region.write_rect(rect.Width as usize, &full, stride, dst, output);
         }
+        self.frame(budget)?
+            .write_rect(self.width as usize, rect, stride, dst, output)
+    }
+
+    /// Creates a downscaled copy whose longest side fits in the configured thumbnail size.
+    fn thumbnail(&self, budget: &Budget) -> windows::core::Result<Self> {
+        let (width, height) = thumbnail::thumbnail_size(
+            self.width,
+            self.height,
+            self.decoded.settings.thumbnail_max_size,
+        );
+        log::trace!(
+            "JXLWICBitmapFrameDecode::thumbnail {}x{} -> {}x{}",
+            self.width,
+            self.height,
+            width,
+            height
+        );
+
+        let scaled = if (width, height) == (self.width, self.height) {
+            self.frame(budget)?
+        } else {
+            let rect = WICRect {
+                X: 0,
+                Y: 0,
+                Width: width as i32,
+                Height: height as i32,
+            };
+            Rc::new(self.scaled(width, height, &rect, budget, false)?)
+        };
+        // Thumbnail consumers like Explorer only take SDR.
+        let (scaled, tone_mapped) = if self.is_hdr() {
+            (Rc::new(self.tone_map(&scaled)), true)
+        } else {
+            (scaled, self.tone_mapped)
+        };
+        Ok(Self {
+            decoded: self.decoded.clone(),
+            index: self.index,
+            width,
+            height,
+            scaled: Some(scaled),
+            tone_mapped,
+        })
     }
 }
 
@@ -264,26 +949,29 @@ impl IWICBitmapSource_Impl for JXLWICBitmapFrameDecode_Impl {
     fn GetPixelFormat(&self) -> windows::core::Result<GUID> {
         log::trace!("JXLWICBitmapFrameDecode::GetPixelFormat");
 
-        match self.pixel_format {
-            PixelFormat::Gray => Ok(GUID_WICPixelFormat16bppGray),
-            // WIC doesn't support Graya, but maybe can be emulated with RGBA
-            PixelFormat::Graya => Err(windows::core::Error::new(
-                WINCODEC_ERR_UNSUPPORTEDPIXELFORMAT,
-                "Gray alpha image is currently not supported",
-            )),
-            PixelFormat::Rgb => Ok(GUID_WICPixelFormat48bppRGB),
-            PixelFormat::Rgba => Ok(GUID_WICPixelFormat64bppRGBA),
-            PixelFormat::Cmyk => Ok(GUID_WICPixelFormat64bppCMYK),
-            PixelFormat::Cmyka => Ok(GUID_WICPixelFormat80bppCMYKAlpha),
+        if self.is_hdr() {
+            Ok(GUID_WICPixelFormat64bppRGBAHalf)
+        } else if self.is_8bit() {
+            // Saves Explorer a format conversion for every thumbnail.
+            Ok(GUID_WICPixelFormat32bppBGRA)
+        } else {
+            Ok(self.wide_pixel_format())
         }
     }
 
     fn GetResolution(&self, pdpix: *mut f64, pdpiy: *mut f64) -> windows::core::Result<()> {
-        log::trace!("JXLWICBitmapFrameDecode::GetResolution");
-        // TODO: Does JXL have resolution info?
+        // JXL itself has no resolution info, but Exif may have one.
+        let (dpi_x, dpi_y) = self
+            .decoded
+            .metadata
+            .exif
+            .as_ref()
+            .and_then(Exif::resolution)
+            .unwrap_or((96f64, 96f64));
+        log::trace!("JXLWICBitmapFrameDecode::GetResolution {}x{}", dpi_x, dpi_y);
         unsafe {
-            *pdpix = 96f64;
-            *pdpiy = 96f64;
+            *pdpix = dpi_x;
+            *pdpiy = dpi_y;
         }
         Ok(())
     }
@@ -296,43 +984,44 @@ impl IWICBitmapSource_Impl for JXLWICBitmapFrameDecode_Impl {
     fn CopyPixels(
         &self,
         prc: *const WICRect,
-        _cbstride: u32,
-        _cbbuffersize: u32,
+        cbstride: u32,
+        cbbuffersize: u32,
         pbbuffer: *mut u8,
     ) -> windows::core::Result<()> {
         log::trace!("JXLWICBitmapFrameDecode::CopyPixels");
 
-        let pbbuffer = pbbuffer as *mut u16;
-
-        let Some(prc) = (unsafe { prc.as_ref() }) else {
+        if pbbuffer.is_null() {
             return Err(E_INVALIDARG.into());
-        };
-
-        log::trace!("JXLWICBitmapFrameDecode::CopyPixels::WICRect {:?}", prc);
+        }
 
-        let channels = self.frame.channels;
-        let buf = &self.frame.buf;
+        let rect = transform::resolve_rect(prc, self.width, self.height)?;
+        log::trace!("JXLWICBitmapFrameDecode::CopyPixels::WICRect {:?}", rect);
 
-        for y in prc.Y..(prc.Y + prc.Height) {
-            let src_offset = (self.width as i32 * y + prc.X) * (channels as i32);
-            let dst_offset = prc.Width * (y - prc.Y) * (channels as i32);
-            unsafe {
-                std::ptr::copy_nonoverlapping(
-                    buf.as_ptr().offset(src_offset as isize),
-                    pbbuffer.offset(dst_offset as isize),
-                    (prc.Width as usize) * channels,
-                );
+        let output = if self.is_8bit() {
+            Output::Bgra {
+                premultiplied: false,
+                dither: self.decoded.settings.dither,
             }
-        }
-
-        Ok(())
+        } else {
+            Output::Native
+        };
+        let dst = unsafe { std::slice::from_raw_parts_mut(pbbuffer, cbbuffersize as usize) };
+        let budget = self.decoded.budget();
+        self.copy_rect(&rect, cbstride as usize, dst, output, &budget)
     }
 }
 
 impl IWICBitmapFrameDecode_Impl for JXLWICBitmapFrameDecode_Impl {
     fn GetMetadataQueryReader(&self) -> windows::core::Result<IWICMetadataQueryReader> {
         log::trace!("JXLWICBitmapFrameDecode::GetMetadataQueryReader");
-        Err(WINCODEC_ERR_UNSUPPORTEDOPERATION.into())
+        // JXL has no per-frame Exif or XMP, so frames share the image metadata like single
+        // frame JPEG does. Only the frame timing is per frame.
+        Ok(JXLMetadataQueryReader::for_frame(
+            self.decoded.metadata.clone(),
+            JXLWICBitmapDecoder::CONTAINER_ID,
+            self.index,
+        )
+        .into())
     }
 
     fn GetColorContexts(
@@ -347,23 +1036,162 @@ impl IWICBitmapFrameDecode_Impl for JXLWICBitmapFrameDecode_Impl {
             ppicolorcontexts,
             pcactualcount
         );
-        unsafe {
-            if let Some(context) = ppicolorcontexts.as_mut()
-                && ccount == 1 {
-                    context
-                        .as_mut()
-                        .expect("There should be a color context here")
-                        .InitializeFromMemory(&self.icc[..])?;
-                }
-            if !pcactualcount.is_null() {
-                *pcactualcount = 1;
-            }
-        }
-        Ok(())
+        color::get_color_contexts(&self.decoded.color, ccount, ppicolorcontexts, pcactualcount)
     }
 
     fn GetThumbnail(&self) -> windows::core::Result<IWICBitmapSource> {
         log::trace!("JXLWICBitmapFrameDecode::GetThumbnail");
-        Err(WINCODEC_ERR_CODECNOTHUMBNAIL.into())
+        let budget = self.decoded.budget();
+        let thumbnail: IWICBitmapFrameDecode = self.thumbnail(&budget)?.into();
+        thumbnail.cast()
+    }
+}
+
+/// Lets WIC request a scaled, cropped and rotated copy in one call instead of transforming
+/// the full-size frame afterwards. The orientation from the image header is already applied
+/// by jxl-oxide, so the transform here is relative to the upright image.
+impl IWICBitmapSourceTransform_Impl for JXLWICBitmapFrameDecode_Impl {
+    fn CopyPixels(
+        &self,
+        prc: *const WICRect,
+        uiwidth: u32,
+        uiheight: u32,
+        pguiddstformat: *const GUID,
+        dsttransform: WICBitmapTransformOptions,
+        nstride: u32,
+        cbbuffersize: u32,
+        pbbuffer: *mut u8,
+    ) -> windows::core::Result<()> {
+        log::trace!(
+            "JXLWICBitmapSourceTransform::CopyPixels {}x{} {:?}",
+            uiwidth,
+            uiheight,
+            dsttransform
+        );
+
+        if pbbuffer.is_null() {
+            return Err(E_INVALIDARG.into());
+        }
+
+        let format = match unsafe { pguiddstformat.as_ref() } {
+            Some(format) => *format,
+            None => self.GetPixelFormat()?,
+        };
+        let Some((tone_map, output)) = self.output(&format) else {
+            return Err(WINCODEC_ERR_UNSUPPORTEDPIXELFORMAT.into());
+        };
+
+        let (mut width, mut height) = (uiwidth, uiheight);
+        self.GetClosestSize(&mut width, &mut height)?;
+        if (width, height) != (uiwidth, uiheight) {
+            return Err(windows::core::Error::new(E_INVALIDARG, "Unsupported scale"));
+        }
+
+        let rect = transform::resolve_rect(prc, width, height)?;
+        log::trace!(
+            "JXLWICBitmapSourceTransform::CopyPixels::WICRect {:?}",
+            rect
+        );
+        // Nothing to copy, and crop can't take an empty rectangle.
+        if rect.Width == 0 || rect.Height == 0 {
+            return Ok(());
+        }
+
+        let dst = unsafe { std::slice::from_raw_parts_mut(pbbuffer, cbbuffersize as usize) };
+        let budget = self.decoded.budget();
+        if (width, height) == (self.width, self.height)
+            && dsttransform == WICBitmapTransformRotate0
+            && !tone_map
+        {
+            return self.copy_rect(&rect, nstride as usize, dst, output, &budget);
+        }
+
+        // Only the pixels in the rectangle are scaled, and huge frames render only the tiles
+        // under it.
+        let mut cropped = if (width, height) != (self.width, self.height) {
+            self.scaled(width, height, &rect, &budget, true)?
+        } else if self.scaled.is_none() && self.decoded.prefers_tiles(self.index, &rect) {
+            self.decoded.region(self.index, &rect, &budget)?
+        } else {
+            transform::crop(&self.frame(&budget)?, width, &rect)
+        };
+        if tone_map {
+            cropped = self.tone_map(&cropped);
+        }
+        if dsttransform == WICBitmapTransformRotate0 {
+            let full = WICRect { X: 0, Y: 0, ..rect };
+            return cropped.write_rect(rect.Width as usize, &full, nstride as usize, dst, output);
+        }
+
+        let (transformed, transformed_width, transformed_height) = transform::rotate_flip(
+            &cropped,
+            rect.Width as u32,
+            rect.Height as u32,
+            dsttransform,
+        );
+        let full = WICRect {
+            X: 0,
+            Y: 0,
+            Width: transformed_width as i32,
+            Height: transformed_height as i32,
+        };
+        transformed.write_rect(
+            transformed_width as usize,
+            &full,
+            nstride as usize,
+            dst,
+            output,
+        )
+    }
+
+    fn GetClosestSize(&self, puiwidth: *mut u32, puiheight: *mut u32) -> windows::core::Result<()> {
+        let (Some(width), Some(height)) =
+            (unsafe { puiwidth.as_mut() }, unsafe { puiheight.as_mut() })
+        else {
+            return Err(E_INVALIDARG.into());
+        };
+
+        // Any downscale is possible, but upscaling is left to WIC.
+        if *width == 0 || *width > self.width {
+            *width = self.width;
+        }
+        if *height == 0 || *height > self.height {
+            *height = self.height;
+        }
+        log::trace!(
+            "JXLWICBitmapSourceTransform::GetClosestSize {}x{}",
+            *width,
+            *height
+        );
+        Ok(())
+    }
+
+    fn GetClosestPixelFormat(&self, pguiddstformat: *mut GUID) -> windows::core::Result<()> {
+        log::trace!("JXLWICBitmapSourceTransform::GetClosestPixelFormat");
+        let Some(format) = (unsafe { pguiddstformat.as_mut() }) else {
+            return Err(E_INVALIDARG.into());
+        };
+        // Supported formats are kept as is. Otherwise HDR frames are tone mapped to 8 bits
+        // unless a float format is asked for.
+        if self.output(format).is_none() {
+            *format = if self.is_hdr() && !JXLWICBitmapDecoder::FLOAT_PIXEL_FORMATS.contains(format)
+            {
+                GUID_WICPixelFormat32bppBGRA
+            } else {
+                self.GetPixelFormat()?
+            };
+        }
+        Ok(())
+    }
+
+    fn DoesSupportTransform(
+        &self,
+        dsttransform: WICBitmapTransformOptions,
+    ) -> windows::core::Result<BOOL> {
+        log::trace!(
+            "JXLWICBitmapSourceTransform::DoesSupportTransform {:?}",
+            dsttransform
+        );
+        Ok(TRUE)
     }
 }
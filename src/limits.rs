@@ -0,0 +1,189 @@
+// Keeps images that claim absurd sizes from exhausting the memory of the host process, e.g.
+// a header of 1073741823 pixels on a side that only takes a few bytes to write. Such a header
+// passes by default, as huge images render in tiles, but the memory limit still bounds every
+// buffer that is allocated for it.
+
+use jxl_oxide::JxlImage;
+use windows::Win32::Graphics::Imaging::{
+    WINCODEC_ERR_IMAGESIZEOUTOFRANGE, WINCODEC_ERR_OUTOFMEMORY, WINCODEC_ERR_VALUEOUTOFRANGE,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The maximum width times height, which is not limited by default.
+    pub max_pixels: u64,
+    /// The maximum number of frames.
+    pub max_frames: u32,
+    /// The maximum bytes for a rendered frame and the frame caches, also handed to jxl-oxide
+    /// for its own allocations.
+    pub max_memory: u64,
+    /// The maximum size of an embedded ICC profile in bytes.
+    pub max_icc_size: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_pixels: u64::MAX,
+            max_frames: 10_000,
+            max_memory: 2048 * 1024 * 1024,
+            max_icc_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// The limits for reading only the headers, as the property store does. Nothing is
+    /// rendered or handed to WIC, so only the allocations of jxl-oxide are limited.
+    pub fn headers_only(self) -> Self {
+        Self {
+            max_pixels: u64::MAX,
+            max_frames: u32::MAX,
+            max_icc_size: u32::MAX,
+            ..self
+        }
+    }
+
+    /// Checks what the image header claims, before any frame is rendered.
+    pub fn check_header(&self, image: &JxlImage) -> windows::core::Result<()> {
+        let size = &image.image_header().size;
+        self.check_pixels(size.width, size.height)?;
+        // jxl-oxide decodes the ICC profile while reading the header, before this runs, so an
+        // oversized profile has already been allocated once. This only rejects the image before
+        // the profile is handed on to WIC and before any frame is rendered.
+        if let Some(icc) = image.original_icc() {
+            self.check_icc_size(icc.len())?;
+        }
+        Ok(())
+    }
+
+    fn check_pixels(&self, width: u32, height: u32) -> windows::core::Result<()> {
+        if width as u64 * height as u64 > self.max_pixels {
+            return Err(windows::core::Error::new(
+                WINCODEC_ERR_IMAGESIZEOUTOFRANGE,
+                format!(
+                    "The image is {} x {}, over the limit of {} pixels",
+                    width, height, self.max_pixels
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_icc_size(&self, len: usize) -> windows::core::Result<()> {
+        if len as u64 > self.max_icc_size as u64 {
+            return Err(windows::core::Error::new(
+                WINCODEC_ERR_VALUEOUTOFRANGE,
+                format!(
+                    "The ICC profile is {} bytes, over the limit of {} bytes",
+                    len, self.max_icc_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check_frames(&self, frame_count: usize) -> windows::core::Result<()> {
+        if frame_count as u64 > self.max_frames as u64 {
+            return Err(windows::core::Error::new(
+                WINCODEC_ERR_VALUEOUTOFRANGE,
+                format!(
+                    "The image has {} frames, over the limit of {}",
+                    frame_count, self.max_frames
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the memory needed to render a frame of `bytes`.
+    pub fn check_memory(&self, bytes: u64) -> windows::core::Result<()> {
+        if bytes > self.max_memory {
+            return Err(windows::core::Error::new(
+                WINCODEC_ERR_OUTOFMEMORY,
+                format!(
+                    "Rendering needs {} bytes, over the limit of {} bytes",
+                    bytes, self.max_memory
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// How many items of `bytes` a cache can keep besides the one being rendered, up to
+    /// `capacity`.
+    pub fn cache_capacity(&self, capacity: usize, bytes: u64) -> usize {
+        let fits = (self.max_memory / bytes.max(1)).saturating_sub(1);
+        capacity.min(usize::try_from(fits).unwrap_or(usize::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alien() -> JxlImage {
+        let file = std::fs::File::open("tests/alien.jxl").unwrap();
+        JxlImage::builder().read(file).unwrap()
+    }
+
+    #[test]
+    fn pixels() {
+        let limits = Limits::default();
+        assert!(
+            limits.check_pixels(u32::MAX, u32::MAX).is_ok(),
+            "no limit by default"
+        );
+
+        let limits = Limits {
+            max_pixels: 1024 * 1024,
+            ..Default::default()
+        };
+        assert!(limits.check_header(&alien()).is_ok(), "1024 x 1024");
+        let limits = Limits {
+            max_pixels: 1024 * 1024 - 1,
+            ..limits
+        };
+        let err = limits.check_header(&alien()).unwrap_err();
+        assert_eq!(err.code(), WINCODEC_ERR_IMAGESIZEOUTOFRANGE);
+        assert!(limits.headers_only().check_header(&alien()).is_ok());
+    }
+
+    #[test]
+    fn frames() {
+        let limits = Limits {
+            max_frames: 2,
+            ..Default::default()
+        };
+        assert!(limits.check_frames(2).is_ok());
+        let err = limits.check_frames(3).unwrap_err();
+        assert_eq!(err.code(), WINCODEC_ERR_VALUEOUTOFRANGE);
+    }
+
+    #[test]
+    fn memory() {
+        let limits = Limits {
+            max_memory: 1000,
+            ..Default::default()
+        };
+        assert!(limits.check_memory(1000).is_ok());
+        let err = limits.check_memory(1001).unwrap_err();
+        assert_eq!(err.code(), WINCODEC_ERR_OUTOFMEMORY);
+        // One 300 byte item is being rendered, so two more fit.
+        assert_eq!(limits.cache_capacity(4, 300), 2);
+        assert_eq!(limits.cache_capacity(1, 300), 1);
+        assert_eq!(limits.cache_capacity(4, 2000), 0);
+    }
+
+    #[test]
+    fn icc_size() {
+        let limits = Limits {
+            max_icc_size: 100,
+            ..Default::default()
+        };
+        assert!(limits.check_icc_size(100).is_ok());
+        let err = limits.check_icc_size(101).unwrap_err();
+        assert_eq!(err.code(), WINCODEC_ERR_VALUEOUTOFRANGE);
+        assert!(limits.headers_only().check_icc_size(usize::MAX).is_ok());
+    }
+}
@@ -0,0 +1,250 @@
+use std::io::{Read, Seek};
+
+use jxl_oxide::{AllocTracker, InitializeResult, JxlImage, Lcms2, UninitializedJxlImage};
+use windows::Win32::Graphics::Imaging::WINCODEC_ERR_BADIMAGE;
+
+use crate::budget::Budget;
+use crate::container::BoxFilter;
+use crate::limits::Limits;
+use crate::mapping::MappedFile;
+use crate::winstream::WinStream;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Streams up to this size are read into one buffer instead of in chunks.
+const CONTIGUOUS_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// The bytes compared between the stream and the file before mapping it.
+const IDENTITY_CHECK_SIZE: usize = 4096;
+
+pub struct LoadedImage {
+    pub image: JxlImage,
+    /// The stream ended before the whole image, e.g. a file that is still downloading. The
+    /// last keyframe is then only partially loaded.
+    pub incomplete: bool,
+}
+
+fn bad_image(err: impl std::fmt::Debug) -> windows::core::Error {
+    windows::core::Error::new(WINCODEC_ERR_BADIMAGE, format!("{:?}", err))
+}
+
+/// Reads an image from an IStream. The stream is left where it started, so that a shared
+/// stream reads the same for the next user.
+pub fn read_stream(
+    mut stream: WinStream,
+    budget: &Budget,
+    limits: &Limits,
+) -> windows::core::Result<LoadedImage> {
+    let len = match stream.size() {
+        Ok(len) => Some(len),
+        Err(err) => {
+            log::trace!("loader::read_stream: {:?}", err);
+            None
+        }
+    };
+    let mapped = map_stream(&mut stream, len);
+    let result = mapped
+        .as_ref()
+        .map(|mapped| read_bytes(&mapped[stream.start() as usize..], budget, limits));
+    let result = match (result, len) {
+        (Some(result), _) => result,
+        (None, Some(len)) if len <= CONTIGUOUS_MAX_SIZE => {
+            read_contiguous(&mut stream, len, budget, limits)
+        }
+        // Seeks over the container boxes that aren't needed.
+        (None, _) => read_image(BoxFilter::new(&mut stream, len), budget, limits),
+    };
+    if let Err(err) = stream.rewind() {
+        log::trace!("loader::read_stream: {:?}", err);
+    }
+    result
+}
+
+/// Maps the file behind the stream, if it is the file that the stream reads. The stream is
+/// left at its start.
+fn map_stream(stream: &mut WinStream, len: Option<u64>) -> Option<MappedFile> {
+    let (path, modified) = stream.file()?;
+    // A relative name would be resolved against the working directory of the host.
+    if !path.is_absolute() {
+        log::trace!("loader::map_stream: {:?} is not an absolute path", path);
+        return None;
+    }
+    let mapped = match MappedFile::open(&path) {
+        Ok(mapped) => mapped,
+        Err(err) => {
+            log::trace!("loader::map_stream: {:?}", err);
+            return None;
+        }
+    };
+    // Otherwise the name is not the file that the stream reads.
+    if Some(mapped.len() as u64) != len.map(|len| len + stream.start()) {
+        log::trace!("loader::map_stream: the file and the stream sizes differ");
+        return None;
+    }
+    if modified != 0 && modified != mapped.modified() {
+        log::trace!("loader::map_stream: the file and the stream times differ");
+        return None;
+    }
+    // A file replaced by another of the same size within the timestamp resolution still
+    // starts with different bytes.
+    let start = stream.start() as usize;
+    let expected = &mapped[start..mapped.len().min(start + IDENTITY_CHECK_SIZE)];
+    let mut actual = vec![0u8; expected.len()];
+    let read = stream.read_exact(&mut actual);
+    if let Err(err) = stream.rewind() {
+        log::trace!("loader::map_stream: {:?}", err);
+        return None;
+    }
+    if let Err(err) = read {
+        log::trace!("loader::map_stream: {:?}", err);
+        return None;
+    }
+    if actual != expected {
+        log::trace!("loader::map_stream: the file and the stream bytes differ");
+        return None;
+    }
+    Some(mapped)
+}
+
+/// Reads the whole stream with a single IStream::Read, when the size is known.
+fn read_contiguous(
+    stream: &mut WinStream,
+    len: u64,
+    budget: &Budget,
+    limits: &Limits,
+) -> windows::core::Result<LoadedImage> {
+    let mut buf = vec![0u8; len as usize];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            // Keep what we have, as read_image does.
+            Err(err) => {
+                log::trace!("loader::read_contiguous: {:?}", err);
+                break;
+            }
+        }
+        // Nothing is fed yet, so there is no partial image to keep.
+        budget.check()?;
+    }
+    read_bytes(&buf[..filled], budget, limits)
+}
+
+/// Feeds bytes to jxl-oxide, initializing the image once the header is in.
+struct Feeder<'a> {
+    uninit: Option<UninitializedJxlImage>,
+    image: Option<JxlImage>,
+    limits: &'a Limits,
+}
+
+impl<'a> Feeder<'a> {
+    fn new(limits: &'a Limits) -> Self {
+        let tracker = AllocTracker::with_limit(limits.max_memory as usize);
+        Self {
+            uninit: Some(JxlImage::builder().alloc_tracker(tracker).build_uninit()),
+            image: None,
+            limits,
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> windows::core::Result<()> {
+        if let Some(mut pending) = self.uninit.take() {
+            pending.feed_bytes(chunk);
+            match pending.try_init().map_err(bad_image)? {
+                InitializeResult::NeedMoreData(next) => self.uninit = Some(next),
+                InitializeResult::Initialized(mut initialized) => {
+                    // Give up before loading the rest of an image that is too large anyway.
+                    self.limits.check_header(&initialized)?;
+                    // Without a CMS, jxl-oxide can't render ICC images in another color
+                    // space, which both HDR and the target color space ask for.
+                    initialized.set_cms(Lcms2);
+                    self.image = Some(initialized);
+                }
+            }
+        } else if let Some(image) = self.image.as_mut() {
+            image.feed_bytes(chunk).map_err(bad_image)?;
+        }
+        Ok(())
+    }
+
+    /// Whether to stop loading the rest. Running out of the budget before the image header
+    /// is an error, and after it leaves the last keyframe partially loaded, which still
+    /// renders from whatever passes there are.
+    fn out_of_budget(&self, budget: &Budget) -> windows::core::Result<bool> {
+        match budget.check() {
+            Ok(()) => Ok(false),
+            Err(err) if self.image.is_none() => Err(err),
+            Err(err) => {
+                log::trace!("loader::Feeder::out_of_budget: stopping early, {:?}", err);
+                Ok(true)
+            }
+        }
+    }
+
+    fn finish(self) -> windows::core::Result<LoadedImage> {
+        let Some(image) = self.image else {
+            return Err(windows::core::Error::new(
+                WINCODEC_ERR_BADIMAGE,
+                "The stream ended before the image header",
+            ));
+        };
+        let incomplete = !image.is_loading_done();
+        if incomplete {
+            log::trace!(
+                "loader::Feeder::finish: incomplete, {} keyframes loaded",
+                image.num_loaded_keyframes()
+            );
+        }
+        Ok(LoadedImage { image, incomplete })
+    }
+}
+
+/// Hands a whole file in memory to jxl-oxide, in chunks so that the budget is checked
+/// between them.
+pub fn read_bytes(
+    bytes: &[u8],
+    budget: &Budget,
+    limits: &Limits,
+) -> windows::core::Result<LoadedImage> {
+    budget.check()?;
+    let mut feeder = Feeder::new(limits);
+    for chunk in bytes.chunks(CHUNK_SIZE) {
+        feeder.feed(chunk)?;
+        if feeder.out_of_budget(budget)? {
+            break;
+        }
+    }
+    feeder.finish()
+}
+
+/// Feeds the stream to jxl-oxide until it ends. Unlike JxlImageBuilder::read, a truncated
+/// stream is not an error as long as the image header is there, so that whatever passes
+/// are loaded can still be rendered. The same goes for running out of the budget.
+pub fn read_image(
+    mut reader: impl Read,
+    budget: &Budget,
+    limits: &Limits,
+) -> windows::core::Result<LoadedImage> {
+    let mut feeder = Feeder::new(limits);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+
+    loop {
+        let count = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            // A network share may fail in the middle. Keep what we have, if anything.
+            Err(err) if feeder.image.is_some() => {
+                log::trace!("loader::read_image: {:?}", err);
+                break;
+            }
+            Err(err) => return Err(bad_image(err)),
+        };
+        feeder.feed(&buf[..count])?;
+        if feeder.out_of_budget(budget)? {
+            break;
+        }
+    }
+
+    feeder.finish()
+}
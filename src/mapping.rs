@@ -0,0 +1,83 @@
+// Maps the file behind a file-backed IStream, so that the image reaches jxl-oxide without
+// an IStream::Read call per chunk.
+
+use std::fs::File;
+use std::os::windows::fs::{MetadataExt, OpenOptionsExt};
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+
+use windows::Win32::Foundation::{CloseHandle, HANDLE};
+use windows::Win32::Storage::FileSystem::{FILE_SHARE_DELETE, FILE_SHARE_READ};
+use windows::Win32::System::Memory::{
+    CreateFileMappingW, FILE_MAP_READ, MEMORY_MAPPED_VIEW_ADDRESS, MapViewOfFile, PAGE_READONLY,
+    UnmapViewOfFile,
+};
+use windows::core::PCWSTR;
+
+pub struct MappedFile {
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+    mapping: HANDLE,
+    len: usize,
+    /// The last write time in FILETIME ticks when the file was mapped
+    modified: u64,
+    // The file is not shared for writing, so that the view is handed out as a slice whose
+    // bytes can't change under it. Opening fails while another handle can write, and the
+    // stream is read instead. It is still shared for deleting, so that the image can be
+    // moved while a viewer decodes it.
+    _file: File,
+}
+
+impl MappedFile {
+    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .share_mode(FILE_SHARE_READ.0 | FILE_SHARE_DELETE.0)
+            .open(path)?;
+        let metadata = file.metadata()?;
+        let len = metadata.len() as usize;
+        let modified = metadata.last_write_time();
+
+        let handle = HANDLE(file.as_raw_handle());
+        let mapping =
+            unsafe { CreateFileMappingW(handle, None, PAGE_READONLY, 0, 0, PCWSTR::null()) }
+                .map_err(|err| {
+                    std::io::Error::other(format!("CreateFileMappingW failed: {}", err.code().0))
+                })?;
+        let view = unsafe { MapViewOfFile(mapping, FILE_MAP_READ, 0, 0, 0) };
+        if view.Value.is_null() {
+            let err = std::io::Error::last_os_error();
+            unsafe { CloseHandle(mapping) };
+            return Err(err);
+        }
+
+        Ok(Self {
+            view,
+            mapping,
+            len,
+            modified,
+            _file: file,
+        })
+    }
+
+    /// The last write time in FILETIME ticks when the file was mapped.
+    pub fn modified(&self) -> u64 {
+        self.modified
+    }
+}
+
+impl std::ops::Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.view.Value as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            UnmapViewOfFile(self.view);
+            CloseHandle(self.mapping);
+        }
+    }
+}
@@ -0,0 +1,633 @@
+use std::{cell::Cell, rc::Rc};
+
+use jxl_oxide::{AuxBoxData, JxlImage};
+use windows as Windows;
+use windows::Win32::{
+    Foundation::*,
+    Graphics::Imaging::*,
+    System::Com::{
+        IEnumString, IEnumString_Impl,
+        StructuredStorage::{
+            InitPropVariantFromBuffer, InitPropVariantFromDoubleVector,
+            InitPropVariantFromInt16Vector, InitPropVariantFromInt32Vector,
+            InitPropVariantFromInt64Vector, InitPropVariantFromStringVector,
+            InitPropVariantFromUInt16Vector, InitPropVariantFromUInt32Vector,
+            InitPropVariantFromUInt64Vector, PVCHF_DEFAULT, PropVariantChangeType,
+        },
+    },
+    System::Variant::VT_LPSTR,
+    UI::Shell::SHStrDupW,
+};
+use windows::core::{
+    GUID, HRESULT, HSTRING, IUnknown, Interface, PCWSTR, PROPVARIANT, PWSTR, implement,
+};
+
+use crate::exif::{self, Exif, Ifd, Value};
+use crate::xmp::{self, Xmp, XmpValue};
+
+/// Animation info from the image header and the frame headers.
+#[derive(Debug)]
+pub struct Animation {
+    pub width: u32,
+    pub height: u32,
+    /// 0 means infinite, as in GIF.
+    pub num_loops: u32,
+    /// The duration of each keyframe in seconds, including the partially loaded one, whose
+    /// header is in even if its pixels are not. It is 0 only if the stream ended before
+    /// that header.
+    pub durations: Vec<f64>,
+}
+
+impl Animation {
+    fn from_image(image: &JxlImage) -> Option<Self> {
+        let animation = image.image_header().metadata.animation.as_ref()?;
+        let seconds_per_tick = if animation.tps_numerator == 0 {
+            0f64
+        } else {
+            animation.tps_denominator as f64 / animation.tps_numerator as f64
+        };
+        // frame_header gives the header of the loading frame for the index after the loaded
+        // keyframes, once the header is in.
+        let frame_count = image.num_loaded_keyframes() + !image.is_loading_done() as usize;
+        let durations = (0..frame_count)
+            .map(|index| {
+                image
+                    .frame_header(index)
+                    .map_or(0f64, |header| header.duration as f64 * seconds_per_tick)
+            })
+            .collect();
+
+        Some(Self {
+            width: image.width(),
+            height: image.height(),
+            num_loops: animation.num_loops,
+            durations,
+        })
+    }
+}
+
+/// Metadata from the `Exif` and `xml ` boxes of the container. jxl-oxide takes care of
+/// decompressing `brob` boxes.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub exif: Option<Exif>,
+    pub xmp: Option<Xmp>,
+    pub animation: Option<Animation>,
+    /// The keyframe that the stream ended in, if it ended before the whole image. The frame
+    /// is rendered from whatever passes were loaded.
+    pub incomplete_frame: Option<usize>,
+}
+
+impl Metadata {
+    pub fn from_image(image: &JxlImage) -> Self {
+        let aux_boxes = image.aux_boxes();
+
+        let exif = match aux_boxes.first_exif() {
+            Ok(AuxBoxData::Data(raw)) => raw
+                .payload()
+                .get(raw.tiff_header_offset() as usize..)
+                .and_then(exif::parse),
+            Ok(_) => None,
+            Err(err) => {
+                log::trace!("Metadata::from_image: bad Exif box {:?}", err);
+                None
+            }
+        };
+        let xmp = match aux_boxes.first_xml() {
+            AuxBoxData::Data(data) => xmp::parse(data),
+            _ => None,
+        };
+
+        let animation = Animation::from_image(image);
+        let incomplete_frame = (!image.is_loading_done()).then(|| image.num_loaded_keyframes());
+
+        log::trace!(
+            "Metadata::from_image: exif {}, xmp {}, animation {}",
+            exif.is_some(),
+            xmp.is_some(),
+            animation.is_some()
+        );
+        Self {
+            exif,
+            xmp,
+            animation,
+            incomplete_frame,
+        }
+    }
+}
+
+/// The root item that is true when the stream ended before the frame, or any frame for the
+/// decoder, was fully loaded, e.g. for a file that is still downloading. It is missing
+/// otherwise.
+const INCOMPLETE: &str = "Incomplete";
+
+/// Query paths follow the JPEG layout so that existing WIC tools work as is, and the GIF
+/// layout for animations so that WIC-based viewers can play them.
+/// https://learn.microsoft.com/en-us/windows/win32/wic/-wic-native-image-format-metadata-queries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Root,
+    App1,
+    Ifd,
+    ExifIfd,
+    GpsIfd,
+    Xmp,
+    LogicalScreenDescriptor,
+    ApplicationExtension,
+    GraphicControlExtension,
+    ImageDescriptor,
+}
+
+impl Location {
+    fn path(self) -> &'static str {
+        match self {
+            Location::Root => "/",
+            Location::App1 => "/app1",
+            Location::Ifd => "/app1/ifd",
+            Location::ExifIfd => "/app1/ifd/exif",
+            Location::GpsIfd => "/app1/ifd/gps",
+            Location::Xmp => "/xmp",
+            Location::LogicalScreenDescriptor => "/logscrdesc",
+            Location::ApplicationExtension => "/appext",
+            Location::GraphicControlExtension => "/grctlext",
+            Location::ImageDescriptor => "/imgdesc",
+        }
+    }
+
+    fn format(self, container: GUID) -> GUID {
+        match self {
+            Location::Root => container,
+            Location::App1 => GUID_MetadataFormatApp1,
+            Location::Ifd => GUID_MetadataFormatIfd,
+            Location::ExifIfd => GUID_MetadataFormatExif,
+            Location::GpsIfd => GUID_MetadataFormatGps,
+            Location::Xmp => GUID_MetadataFormatXMP,
+            Location::LogicalScreenDescriptor => GUID_MetadataFormatLSD,
+            Location::ApplicationExtension => GUID_MetadataFormatAPE,
+            Location::GraphicControlExtension => GUID_MetadataFormatGCE,
+            Location::ImageDescriptor => GUID_MetadataFormatIMD,
+        }
+    }
+}
+
+/// Parses `{ushort=271}`-style item names, and plain numbers.
+fn parse_index(name: &str) -> Option<u32> {
+    let value = match name.strip_prefix('{').and_then(|n| n.strip_suffix('}')) {
+        Some(typed) => typed.split_once('=')?.1,
+        None => name,
+    };
+    value.trim().parse().ok()
+}
+
+fn scalar_or_vector<T: Copy + Into<PROPVARIANT>>(
+    values: &[T],
+    vector: unsafe fn(Option<&[T]>) -> windows::core::Result<PROPVARIANT>,
+) -> windows::core::Result<PROPVARIANT> {
+    match values {
+        [value] => Ok((*value).into()),
+        _ => unsafe { vector(Some(values)) },
+    }
+}
+
+/// Makes a VT_VECTOR | VT_UI1, which WIC uses for BYTE and UNDEFINED values.
+fn bytes_to_propvariant(bytes: &[u8]) -> windows::core::Result<PROPVARIANT> {
+    unsafe { InitPropVariantFromBuffer(bytes.as_ptr() as _, bytes.len() as u32) }
+}
+
+/// Makes a VT_LPSTR, which WIC uses for ASCII values.
+fn ascii_to_propvariant(text: &str) -> windows::core::Result<PROPVARIANT> {
+    let mut variant = PROPVARIANT::new();
+    unsafe {
+        PropVariantChangeType(
+            &mut variant,
+            &PROPVARIANT::from(text),
+            PVCHF_DEFAULT,
+            VT_LPSTR,
+        )?
+    };
+    Ok(variant)
+}
+
+/// Converts the value in the same way as the WIC IFD reader does.
+fn exif_to_propvariant(value: &Value) -> windows::core::Result<PROPVARIANT> {
+    match value {
+        Value::Ascii(text) => ascii_to_propvariant(text),
+        Value::Byte(bytes) | Value::Undefined(bytes) => bytes_to_propvariant(bytes),
+        Value::Short(values) => scalar_or_vector(values, InitPropVariantFromUInt16Vector),
+        Value::Long(values) => scalar_or_vector(values, InitPropVariantFromUInt32Vector),
+        // WIC packs the numerator into the low part and the denominator into the high part.
+        Value::Rational(values) => {
+            let packed: Vec<u64> = values
+                .iter()
+                .map(|&(num, denom)| ((denom as u64) << 32) | num as u64)
+                .collect();
+            scalar_or_vector(&packed, InitPropVariantFromUInt64Vector)
+        }
+        Value::SByte(values) => {
+            let values: Vec<i16> = values.iter().map(|&v| v as i16).collect();
+            scalar_or_vector(&values, InitPropVariantFromInt16Vector)
+        }
+        Value::SShort(values) => scalar_or_vector(values, InitPropVariantFromInt16Vector),
+        Value::SLong(values) => scalar_or_vector(values, InitPropVariantFromInt32Vector),
+        Value::SRational(values) => {
+            let packed: Vec<i64> = values
+                .iter()
+                .map(|&(num, denom)| ((denom as i64) << 32) | (num as u32 as i64))
+                .collect();
+            scalar_or_vector(&packed, InitPropVariantFromInt64Vector)
+        }
+        Value::Float(values) => {
+            let values: Vec<f64> = values.iter().map(|&v| v as f64).collect();
+            scalar_or_vector(&values, InitPropVariantFromDoubleVector)
+        }
+        Value::Double(values) => scalar_or_vector(values, InitPropVariantFromDoubleVector),
+    }
+}
+
+fn strings_to_propvariant(items: &[&str]) -> windows::core::Result<PROPVARIANT> {
+    let items: Vec<HSTRING> = items.iter().map(|&item| HSTRING::from(item)).collect();
+    let pointers: Vec<PCWSTR> = items.iter().map(|item| PCWSTR(item.as_ptr())).collect();
+    unsafe { InitPropVariantFromStringVector(Some(&pointers)) }
+}
+
+/// Returns the whole value, or with `item` either a language of rdf:Alt or an index.
+fn xmp_to_propvariant(value: &XmpValue, item: Option<&str>) -> Option<PROPVARIANT> {
+    let text = match (value, item) {
+        (_, None) => match value {
+            XmpValue::Array(items) => {
+                let items: Vec<&str> = items.iter().map(String::as_str).collect();
+                return strings_to_propvariant(&items).ok();
+            }
+            _ => value.as_text()?,
+        },
+        (XmpValue::LangAlt(items), Some(lang)) => items
+            .iter()
+            .find(|(item_lang, _)| item_lang.eq_ignore_ascii_case(lang))
+            .map(|(_, text)| text.as_str())?,
+        (XmpValue::Array(items), Some(index)) => items.get(parse_index(index)? as usize)?.as_str(),
+        (XmpValue::Text(_), Some(_)) => return None,
+    };
+    Some(PROPVARIANT::from(text))
+}
+
+#[implement(Windows::Win32::Graphics::Imaging::IWICMetadataQueryReader)]
+pub struct JXLMetadataQueryReader {
+    metadata: Rc<Metadata>,
+    location: Location,
+    container_format: GUID,
+    /// The keyframe index for frame level readers
+    frame_index: Option<usize>,
+}
+
+impl JXLMetadataQueryReader {
+    /// Creates the decoder level reader. `container_format` is what the root reports as its
+    /// format.
+    pub fn new(metadata: Rc<Metadata>, container_format: GUID) -> Self {
+        Self {
+            metadata,
+            location: Location::Root,
+            container_format,
+            frame_index: None,
+        }
+    }
+
+    /// Creates the frame level reader, which has the frame timing on top of the image
+    /// metadata.
+    pub fn for_frame(metadata: Rc<Metadata>, container_format: GUID, frame_index: usize) -> Self {
+        Self {
+            frame_index: Some(frame_index),
+            ..Self::new(metadata, container_format)
+        }
+    }
+
+    fn nested(&self, location: Location) -> Self {
+        Self {
+            metadata: self.metadata.clone(),
+            location,
+            container_format: self.container_format,
+            frame_index: self.frame_index,
+        }
+    }
+
+    /// Returns the items of the GIF blocks in the same types as the WIC GIF decoder.
+    fn animation_items(&self, location: Location) -> Vec<(&'static str, PROPVARIANT)> {
+        let Some(animation) = self.metadata.animation.as_ref() else {
+            return vec![];
+        };
+        let width = animation.width.min(u16::MAX as u32) as u16;
+        let height = animation.height.min(u16::MAX as u32) as u16;
+
+        match location {
+            Location::LogicalScreenDescriptor => {
+                vec![("Width", width.into()), ("Height", height.into())]
+            }
+            Location::ApplicationExtension => {
+                let [low, high] = (animation.num_loops.min(u16::MAX as u32) as u16).to_le_bytes();
+                [
+                    ("Application", bytes_to_propvariant(b"NETSCAPE2.0")),
+                    // The sub-block size, the sub-block ID, and the loop count
+                    ("Data", bytes_to_propvariant(&[3, 1, low, high, 0])),
+                ]
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, value.ok()?)))
+                .collect()
+            }
+            Location::GraphicControlExtension => {
+                let Some(&duration) = self.frame_index.and_then(|i| animation.durations.get(i))
+                else {
+                    return vec![];
+                };
+                // In 1/100 seconds
+                let delay = (duration * 100f64).round().clamp(0f64, u16::MAX as f64) as u16;
+                vec![
+                    ("Delay", delay.into()),
+                    // jxl-oxide renders keyframes that are already blended onto the canvas
+                    // with their own blend modes, so the viewer should replace the whole
+                    // frame. Neither the blend modes nor the JXL frame positions apply to
+                    // the frames WIC gets.
+                    ("Disposal", 2u8.into()),
+                ]
+            }
+            Location::ImageDescriptor => vec![
+                ("Left", 0u16.into()),
+                ("Top", 0u16.into()),
+                ("Width", width.into()),
+                ("Height", height.into()),
+            ],
+            _ => vec![],
+        }
+    }
+
+    /// Whether the frame, or any frame for the decoder, is only partially loaded.
+    fn incomplete(&self) -> bool {
+        match (self.metadata.incomplete_frame, self.frame_index) {
+            (Some(incomplete), Some(index)) => incomplete == index,
+            (incomplete, None) => incomplete.is_some(),
+            (None, Some(_)) => false,
+        }
+    }
+
+    fn ifd(&self, location: Location) -> Option<&Ifd> {
+        let exif = self.metadata.exif.as_ref()?;
+        let ifd = match location {
+            Location::Ifd => &exif.ifd0,
+            Location::ExifIfd => &exif.exif,
+            Location::GpsIfd => &exif.gps,
+            _ => return None,
+        };
+        (!ifd.is_empty() || location == Location::Ifd).then_some(ifd)
+    }
+
+    fn children(&self, location: Location) -> Vec<(String, Option<Location>)> {
+        let mut children = vec![];
+        match location {
+            Location::Root => {
+                if self.incomplete() {
+                    children.push((format!("/{}", INCOMPLETE), None));
+                }
+                if self.metadata.exif.is_some() {
+                    children.push(("/app1".to_string(), Some(Location::App1)));
+                }
+                if self.metadata.xmp.is_some() {
+                    children.push(("/xmp".to_string(), Some(Location::Xmp)));
+                }
+                if self.metadata.animation.is_some() {
+                    let blocks = match self.frame_index {
+                        None => [
+                            ("/logscrdesc", Location::LogicalScreenDescriptor),
+                            ("/appext", Location::ApplicationExtension),
+                        ],
+                        Some(_) => [
+                            ("/grctlext", Location::GraphicControlExtension),
+                            ("/imgdesc", Location::ImageDescriptor),
+                        ],
+                    };
+                    for (name, child) in blocks {
+                        children.push((name.to_string(), Some(child)));
+                    }
+                }
+            }
+            Location::App1 => children.push(("/ifd".to_string(), Some(Location::Ifd))),
+            Location::Ifd | Location::ExifIfd | Location::GpsIfd => {
+                if let Some(ifd) = self.ifd(location) {
+                    for entry in &ifd.entries {
+                        children.push((format!("/{{ushort={}}}", entry.tag), None));
+                    }
+                }
+                if location == Location::Ifd {
+                    for (name, child) in [("/exif", Location::ExifIfd), ("/gps", Location::GpsIfd)]
+                    {
+                        if self.ifd(child).is_some() {
+                            children.push((name.to_string(), Some(child)));
+                        }
+                    }
+                }
+            }
+            Location::Xmp => {
+                if let Some(xmp) = self.metadata.xmp.as_ref() {
+                    for (name, _) in &xmp.properties {
+                        children.push((format!("/{}", name), None));
+                    }
+                }
+            }
+            Location::LogicalScreenDescriptor
+            | Location::ApplicationExtension
+            | Location::GraphicControlExtension
+            | Location::ImageDescriptor => {
+                for (name, _) in self.animation_items(location) {
+                    children.push((format!("/{}", name), None));
+                }
+            }
+        }
+        children
+    }
+
+    fn child_location(&self, location: Location, name: &str) -> Option<Location> {
+        self.children(location)
+            .into_iter()
+            .find(|(child, _)| child[1..].eq_ignore_ascii_case(name))
+            .and_then(|(_, child_location)| child_location)
+    }
+
+    fn query(&self, name: &str) -> Option<PROPVARIANT> {
+        let mut location = self.location;
+        let segments: Vec<&str> = name.split('/').filter(|s| !s.is_empty()).collect();
+
+        for (i, &segment) in segments.iter().enumerate() {
+            if let Some(child) = self.child_location(location, segment) {
+                location = child;
+                continue;
+            }
+
+            let rest = &segments[i + 1..];
+            return match location {
+                Location::Root
+                    if rest.is_empty()
+                        && segment.eq_ignore_ascii_case(INCOMPLETE)
+                        && self.incomplete() =>
+                {
+                    Some(true.into())
+                }
+                Location::Ifd | Location::ExifIfd | Location::GpsIfd if rest.is_empty() => {
+                    let tag = u16::try_from(parse_index(segment)?).ok()?;
+                    exif_to_propvariant(self.ifd(location)?.get(tag)?).ok()
+                }
+                Location::Xmp if rest.len() <= 1 => {
+                    let value = self.metadata.xmp.as_ref()?.get(segment)?;
+                    xmp_to_propvariant(value, rest.first().copied())
+                }
+                Location::LogicalScreenDescriptor
+                | Location::ApplicationExtension
+                | Location::GraphicControlExtension
+                | Location::ImageDescriptor
+                    if rest.is_empty() =>
+                {
+                    self.animation_items(location)
+                        .into_iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(segment))
+                        .map(|(_, value)| value)
+                }
+                _ => None,
+            };
+        }
+
+        if location == self.location {
+            return None;
+        }
+        let reader: IWICMetadataQueryReader = self.nested(location).into();
+        reader.cast::<IUnknown>().ok().map(PROPVARIANT::from)
+    }
+}
+
+impl IWICMetadataQueryReader_Impl for JXLMetadataQueryReader_Impl {
+    fn GetContainerFormat(&self) -> windows::core::Result<GUID> {
+        log::trace!("JXLMetadataQueryReader::GetContainerFormat");
+        Ok(self.location.format(self.container_format))
+    }
+
+    fn GetLocation(
+        &self,
+        cchmaxlength: u32,
+        wznamespace: &PWSTR,
+        pcchactuallength: *mut u32,
+    ) -> windows::core::Result<()> {
+        log::trace!("JXLMetadataQueryReader::GetLocation");
+        let location: Vec<u16> = self
+            .location
+            .path()
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
+
+        if let Some(actual_length) = unsafe { pcchactuallength.as_mut() } {
+            *actual_length = location.len() as u32;
+        } else if wznamespace.is_null() {
+            return Err(E_INVALIDARG.into());
+        }
+
+        if wznamespace.is_null() {
+            return Ok(());
+        }
+        if (cchmaxlength as usize) < location.len() {
+            return Err(WINCODEC_ERR_INSUFFICIENTBUFFER.into());
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(location.as_ptr(), wznamespace.0, location.len());
+        }
+        Ok(())
+    }
+
+    fn GetMetadataByName(
+        &self,
+        wzname: &PCWSTR,
+        pvarvalue: *mut PROPVARIANT,
+    ) -> windows::core::Result<()> {
+        if wzname.is_null() {
+            return Err(E_INVALIDARG.into());
+        }
+        let name = unsafe { wzname.to_string() }.map_err(|_| E_INVALIDARG)?;
+        log::trace!("JXLMetadataQueryReader::GetMetadataByName {}", name);
+
+        let Some(value) = self.query(&name) else {
+            return Err(WINCODEC_ERR_PROPERTYNOTFOUND.into());
+        };
+        // A null output only asks whether the item exists.
+        if !pvarvalue.is_null() {
+            unsafe { pvarvalue.write(value) };
+        }
+        Ok(())
+    }
+
+    fn GetEnumerator(&self) -> windows::core::Result<IEnumString> {
+        log::trace!("JXLMetadataQueryReader::GetEnumerator");
+        let items = self
+            .children(self.location)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        Ok(JXLEnumString::new(Rc::new(items)).into())
+    }
+}
+
+#[implement(Windows::Win32::System::Com::IEnumString)]
+struct JXLEnumString {
+    items: Rc<Vec<String>>,
+    position: Cell<usize>,
+}
+
+impl JXLEnumString {
+    fn new(items: Rc<Vec<String>>) -> Self {
+        Self {
+            items,
+            position: Cell::new(0),
+        }
+    }
+}
+
+impl IEnumString_Impl for JXLEnumString_Impl {
+    fn Next(&self, celt: u32, rgelt: *mut PWSTR, pceltfetched: *mut u32) -> HRESULT {
+        if rgelt.is_null() {
+            return E_POINTER;
+        }
+
+        let start = self.position.get();
+        let end = start.saturating_add(celt as usize).min(self.items.len());
+        for (i, item) in self.items[start..end].iter().enumerate() {
+            match unsafe { SHStrDupW(&HSTRING::from(item.as_str())) } {
+                Ok(item) => unsafe { *rgelt.add(i) = item },
+                Err(err) => return err.code(),
+            }
+            self.position.set(start + i + 1);
+        }
+
+        if let Some(fetched) = unsafe { pceltfetched.as_mut() } {
+            *fetched = (end - start) as u32;
+        }
+        if end - start == celt as usize {
+            S_OK
+        } else {
+            S_FALSE
+        }
+    }
+
+    fn Skip(&self, celt: u32) -> HRESULT {
+        let position = self.position.get().saturating_add(celt as usize);
+        self.position.set(position.min(self.items.len()));
+        if position <= self.items.len() {
+            S_OK
+        } else {
+            S_FALSE
+        }
+    }
+
+    fn Reset(&self) -> windows::core::Result<()> {
+        self.position.set(0);
+        Ok(())
+    }
+
+    fn Clone(&self) -> windows::core::Result<IEnumString> {
+        let clone = JXLEnumString::new(self.items.clone());
+        clone.position.set(self.position.get());
+        Ok(clone.into())
+    }
+}
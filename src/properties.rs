@@ -1,21 +1,39 @@
-use std::io::BufReader;
+use std::cell::RefCell;
+use std::io::{Read, Seek};
 
+use jxl_oxide::{AuxBoxData, PixelFormat};
 use windows as Windows;
 use windows::Win32::{
     Foundation::*,
     System::Com::{
-        IStream,
-        StructuredStorage::{InitPropVariantFromStringVector, InitPropVariantFromUInt32Vector},
+        CoTaskMemFree, IStream, STGC_DEFAULT, STGM_READWRITE,
+        StructuredStorage::{
+            InitPropVariantFromDoubleVector, InitPropVariantFromFileTime,
+            InitPropVariantFromStringVector, InitPropVariantFromUInt32Vector,
+            PropVariantGetElementCount, PropVariantGetStringElem,
+        },
     },
-    UI::Shell::PropertiesSystem::{
-        IInitializeWithStream_Impl, IPropertyStore_Impl, IPropertyStoreCache,
-        IPropertyStoreCapabilities_Impl, PROPERTYKEY, PSC_READONLY, PSCreateMemoryPropertyStore,
+    System::Time::{SystemTimeToFileTime, TzSpecificLocalTimeToSystemTime},
+    UI::Shell::{
+        IDestinationStreamFactory,
+        PropertiesSystem::{
+            IInitializeWithStream_Impl, IPropertyStore_Impl, IPropertyStoreCache,
+            IPropertyStoreCapabilities_Impl, PROPERTYKEY, PSC_DIRTY, PSC_NORMAL, PSC_READONLY,
+            PSCreateMemoryPropertyStore,
+        },
     },
 };
 use windows::core::{GUID, HSTRING, Interface, PCWSTR, PROPVARIANT, implement};
 
+use crate::budget::Budget;
+use crate::color;
+use crate::container;
+use crate::exif::{self, DateTime, Exif, Value};
+use crate::loader::{self, LoadedImage};
+use crate::metadata::Metadata;
+use crate::settings::Settings;
 use crate::winstream::WinStream;
-use jxl_oxide::JxlImage;
+use crate::xmp::{self, Xmp, XmpValue};
 
 #[implement(
     Windows::Win32::UI::Shell::PropertiesSystem::IInitializeWithStream,
@@ -25,6 +43,8 @@ use jxl_oxide::JxlImage;
 #[derive(Default)]
 pub struct JXLPropertyStore {
     props: Option<IPropertyStoreCache>,
+    /// The stream to commit to, if it was opened for writing
+    stream: RefCell<Option<IStream>>,
 }
 
 impl JXLPropertyStore {
@@ -42,13 +62,14 @@ impl JXLPropertyStore {
 }
 
 impl IInitializeWithStream_Impl for JXLPropertyStore_Impl {
-    fn Initialize(&self, pstream: Option<&IStream>, _grfmode: u32) -> windows::core::Result<()> {
+    fn Initialize(&self, pstream: Option<&IStream>, grfmode: u32) -> windows::core::Result<()> {
         let stream = WinStream::from(pstream.unwrap());
-        let reader = BufReader::new(stream);
-
-        let image = JxlImage::builder().read(reader).map_err(|err| {
-            windows::core::Error::new(WINCODEC_ERR_BADIMAGE, format!("{:?}", err))
-        })?;
+        let settings = Settings::load();
+        let budget = Budget::new(settings.decode_timeout, Default::default());
+        // The header is enough for the properties, so a truncated file is fine, and nothing
+        // is rendered, so neither is a huge one.
+        let limits = settings.limits.headers_only();
+        let LoadedImage { image, .. } = loader::read_stream(stream, &budget, &limits)?;
 
         let (width, height, _left, _top) = image.image_header().metadata.apply_orientation(
             image.image_header().size.width,
@@ -103,10 +124,482 @@ impl IInitializeWithStream_Impl for JXLPropertyStore_Impl {
         };
         unsafe { props.SetValueAndState(&propkey, &variant, PSC_READONLY)? };
 
+        // Alpha counts in the bit depth like in 32 bit PNGs, with its own bit depth.
+        let color_channels = match image.pixel_format() {
+            PixelFormat::Gray | PixelFormat::Graya => 1,
+            PixelFormat::Rgb | PixelFormat::Rgba => 3,
+            PixelFormat::Cmyk | PixelFormat::Cmyka => 4,
+        };
+        let image_metadata = &image.image_header().metadata;
+        let alpha_bits = image_metadata
+            .ec_info
+            .iter()
+            .find(|info| info.is_alpha())
+            .map(|info| info.bit_depth.bits_per_sample());
+        let bits_per_sample = image_metadata.bit_depth.bits_per_sample();
+        let variant = PROPVARIANT::from(bits_per_sample * color_channels + alpha_bits.unwrap_or(0));
+        let propkey = PROPERTYKEY {
+            fmtid: PSGUID_IMAGESUMMARYINFORMATION,
+            pid: 7,
+        };
+        unsafe { props.SetValueAndState(&propkey, &variant, PSC_READONLY)? };
+
+        let variant = PROPVARIANT::from(alpha_bits.is_some());
+        unsafe { props.SetValueAndState(&PKEY_HAS_ALPHA, &variant, PSC_READONLY)? };
+
+        // The EXIF ColorSpace tag: sRGB, or uncalibrated for anything else
+        let color_space: u16 = if color::is_srgb(&image) { 1 } else { 0xffff };
+        let variant = PROPVARIANT::from(color_space);
+        let propkey = PROPERTYKEY {
+            fmtid: PSGUID_PHOTO,
+            pid: 40961,
+        };
+        unsafe { props.SetValueAndState(&propkey, &variant, PSC_READONLY)? };
+
+        let metadata = Metadata::from_image(&image);
+        if let Some(exif) = &metadata.exif {
+            set_photo_properties(props, exif)?;
+            set_gps_properties(props, exif)?;
+        }
+        if let Some(xmp) = &metadata.xmp {
+            set_xmp_properties(props, xmp)?;
+        }
+
+        if grfmode & STGM_READWRITE.0 != 0 {
+            if has_jpeg_reconstruction(&mut WinStream::from(pstream.unwrap()))? {
+                log::trace!("JXLPropertyStore::Initialize: read-only for JPEG reconstruction");
+            } else {
+                *self.stream.borrow_mut() = pstream.cloned();
+            }
+        }
+
         Ok(())
     }
 }
 
+/// Whether the image has an alpha channel. Windows has no such property, so this one is ours,
+/// described to Explorer as JxlWinthumb.Image.HasAlpha by the schema that registration
+/// installs.
+pub const PKEY_HAS_ALPHA: PROPERTYKEY = PROPERTYKEY {
+    fmtid: GUID::from_u128(0x6a5c3f1e_2b7d_4e8a_9c41_5d0e8f7b2a63),
+    pid: 2,
+};
+
+// XXX: These are copied from um/propkey.h, like PSGUID_IMAGESUMMARYINFORMATION.
+const PSGUID_PHOTO: GUID = GUID::from_u128(0x14B81DA1_0135_4D31_96D9_6CBFC9671A99);
+// The property IDs are the EXIF tags.
+fn photo_key(tag: u16) -> PROPERTYKEY {
+    PROPERTYKEY {
+        fmtid: PSGUID_PHOTO,
+        pid: tag as u32,
+    }
+}
+
+// System.Photo.FocalLengthInFilm is the one camera property that isn't keyed by its tag.
+const PKEY_FOCAL_LENGTH_IN_FILM: PROPERTYKEY = PROPERTYKEY {
+    fmtid: GUID::from_u128(0xA0E74609_B84D_4F49_B860_462BD9971F98),
+    pid: 100,
+};
+
+// Each System.GPS property has a format ID of its own.
+fn gps_key(fmtid: u128) -> PROPERTYKEY {
+    PROPERTYKEY {
+        fmtid: GUID::from_u128(fmtid),
+        pid: 100,
+    }
+}
+
+const FMTID_GPS_LATITUDE: u128 = 0x8727CFFF_4868_4EC6_AD5B_81B98521D1AB;
+const FMTID_GPS_LATITUDE_REF: u128 = 0x029C0252_5B86_46C7_ACA0_2769FFC8E3D4;
+const FMTID_GPS_LATITUDE_DECIMAL: u128 = 0x0F55CDE2_4F49_450D_92C1_DCD16301B1B7;
+const FMTID_GPS_LONGITUDE: u128 = 0xC4C4DBB2_B593_466B_BBDA_D03D27D5E43A;
+const FMTID_GPS_LONGITUDE_REF: u128 = 0x33DCF22B_28D5_464C_8035_1EE9EFD25278;
+const FMTID_GPS_LONGITUDE_DECIMAL: u128 = 0x4679C1B5_844D_4590_BAF5_F322231F1B81;
+const FMTID_GPS_ALTITUDE: u128 = 0x827EDB4F_5B73_44A7_891D_FDFFABEA35CA;
+const FMTID_GPS_ALTITUDE_REF: u128 = 0x46AC629D_75EA_4515_867F_6DC4321C5844;
+
+fn set_value(
+    props: &IPropertyStoreCache,
+    key: &PROPERTYKEY,
+    variant: &PROPVARIANT,
+) -> windows::core::Result<()> {
+    let state = if xmp_name(key).is_some() {
+        PSC_NORMAL
+    } else {
+        PSC_READONLY
+    };
+    unsafe { props.SetValueAndState(key, variant, state) }
+}
+
+fn set_string(
+    props: &IPropertyStoreCache,
+    key: &PROPERTYKEY,
+    value: &str,
+) -> windows::core::Result<()> {
+    set_strings(props, key, &[value])
+}
+
+/// Sets the non-empty ones of `values`, if any.
+fn set_strings(
+    props: &IPropertyStoreCache,
+    key: &PROPERTYKEY,
+    values: &[&str],
+) -> windows::core::Result<()> {
+    let values: Vec<HSTRING> = values
+        .iter()
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(HSTRING::from)
+        .collect();
+    if values.is_empty() {
+        return Ok(());
+    }
+    let pointers: Vec<PCWSTR> = values.iter().map(|value| PCWSTR(value.as_ptr())).collect();
+    let variant = unsafe { InitPropVariantFromStringVector(Some(&pointers))? };
+    set_value(props, key, &variant)
+}
+
+// XXX: Also copied from um/propkey.h
+const FMTID_SUMMARYINFORMATION: GUID = GUID::from_u128(0xF29F85E0_4FF9_1068_AB91_08002B27B3D9);
+const PKEY_TITLE: PROPERTYKEY = PROPERTYKEY {
+    fmtid: FMTID_SUMMARYINFORMATION,
+    pid: 2,
+};
+const PKEY_AUTHOR: PROPERTYKEY = PROPERTYKEY {
+    fmtid: FMTID_SUMMARYINFORMATION,
+    pid: 4,
+};
+const PKEY_KEYWORDS: PROPERTYKEY = PROPERTYKEY {
+    fmtid: FMTID_SUMMARYINFORMATION,
+    pid: 5,
+};
+const PKEY_COMMENT: PROPERTYKEY = PROPERTYKEY {
+    fmtid: FMTID_SUMMARYINFORMATION,
+    pid: 6,
+};
+const PKEY_RATING: PROPERTYKEY = PROPERTYKEY {
+    fmtid: GUID::from_u128(0x64440492_4C8B_11D1_8B70_080036B11A03),
+    pid: 9,
+};
+
+/// The properties that are written back to the XMP they are read from.
+const WRITABLE: [(PROPERTYKEY, &str); 5] = [
+    (PKEY_RATING, "xmp:Rating"),
+    (PKEY_TITLE, "dc:title"),
+    (PKEY_KEYWORDS, "dc:subject"),
+    (PKEY_AUTHOR, "dc:creator"),
+    (PKEY_COMMENT, "dc:description"),
+];
+
+fn xmp_name(key: &PROPERTYKEY) -> Option<&'static str> {
+    WRITABLE
+        .iter()
+        .find(|(writable, _)| writable == key)
+        .map(|(_, name)| *name)
+}
+
+/// Maps the XMP stars to the 1 to 99 scale of System.Rating, the way Windows Photo Gallery
+/// does. 0 is unrated, and -1 (rejected) has no counterpart.
+fn stars_to_rating(stars: &str) -> Option<u32> {
+    let stars = stars.trim().parse::<f64>().ok()?.round();
+    match stars as i32 {
+        0 => Some(0),
+        1 => Some(1),
+        2 => Some(25),
+        3 => Some(50),
+        4 => Some(75),
+        5 => Some(99),
+        _ => None,
+    }
+}
+
+/// The other way around, with the ranges Windows uses for the stars it shows.
+fn rating_to_stars(rating: u32) -> u32 {
+    match rating {
+        0 => 0,
+        1..=12 => 1,
+        13..=37 => 2,
+        38..=62 => 3,
+        63..=87 => 4,
+        _ => 5,
+    }
+}
+
+/// Reads a string or a vector of strings, leaving out the empty ones.
+fn propvariant_to_strings(variant: &PROPVARIANT) -> windows::core::Result<Vec<String>> {
+    let count = unsafe { PropVariantGetElementCount(variant) };
+    let mut items = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        let item = unsafe { PropVariantGetStringElem(variant, index)? };
+        let text = unsafe { item.to_string() };
+        unsafe { CoTaskMemFree(Some(item.0 as _)) };
+        let text = text.map_err(|_| windows::core::Error::from(E_INVALIDARG))?;
+        if !text.trim().is_empty() {
+            items.push(text.trim().to_string());
+        }
+    }
+    Ok(items)
+}
+
+/// Converts a value set on the store to what goes into the XMP. `None` removes the property.
+fn propvariant_to_xmp(
+    name: &str,
+    variant: &PROPVARIANT,
+) -> windows::core::Result<Option<XmpValue>> {
+    if variant.is_empty() {
+        return Ok(None);
+    }
+    if name == "xmp:Rating" {
+        let rating = u32::try_from(variant)?;
+        return Ok(Some(XmpValue::Text(rating_to_stars(rating).to_string())));
+    }
+
+    let mut items = propvariant_to_strings(variant)?;
+    if items.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(match name {
+        // Alternatives in other languages are dropped, as they would no longer match.
+        "dc:title" | "dc:description" => {
+            XmpValue::LangAlt(vec![("x-default".to_string(), items.swap_remove(0))])
+        }
+        _ => XmpValue::Array(items),
+    }))
+}
+
+/// Publishes the descriptive XMP properties that photo managers like Lightroom write.
+fn set_xmp_properties(props: &IPropertyStoreCache, xmp: &Xmp) -> windows::core::Result<()> {
+    if let Some(rating) = xmp
+        .get("xmp:Rating")
+        .and_then(XmpValue::as_text)
+        .and_then(stars_to_rating)
+    {
+        set_value(props, &PKEY_RATING, &PROPVARIANT::from(rating))?;
+    }
+
+    // Alternatives in other languages are left out, as the shell shows one.
+    for (name, key) in [("dc:title", &PKEY_TITLE), ("dc:description", &PKEY_COMMENT)] {
+        if let Some(text) = xmp.get(name).and_then(XmpValue::as_text) {
+            set_string(props, key, text)?;
+        }
+    }
+
+    for (name, key) in [("dc:subject", &PKEY_KEYWORDS), ("dc:creator", &PKEY_AUTHOR)] {
+        let items: Vec<&str> = match xmp.get(name) {
+            Some(XmpValue::Array(items)) => items.iter().map(String::as_str).collect(),
+            Some(value) => value.as_text().into_iter().collect(),
+            None => continue,
+        };
+        set_strings(props, key, &items)?;
+    }
+
+    Ok(())
+}
+
+/// Publishes the camera fields of the Exif box as System.Photo properties, like the
+/// Windows JPEG property handler does.
+fn set_photo_properties(props: &IPropertyStoreCache, exif: &Exif) -> windows::core::Result<()> {
+    for tag in [exif::TAG_MAKE, exif::TAG_MODEL] {
+        if let Some(value) = exif.ifd0.get(tag).and_then(Value::as_str) {
+            set_string(props, &photo_key(tag), value)?;
+        }
+    }
+
+    for tag in [
+        exif::TAG_EXPOSURE_TIME,
+        exif::TAG_F_NUMBER,
+        exif::TAG_EXPOSURE_BIAS,
+        exif::TAG_FOCAL_LENGTH,
+    ] {
+        if let Some(value) = exif.exif.get(tag).and_then(Value::as_f64) {
+            set_value(props, &photo_key(tag), &PROPVARIANT::from(value))?;
+        }
+    }
+
+    let as_u16 = |tag| {
+        exif.exif
+            .get(tag)
+            .and_then(Value::as_u32)
+            .and_then(|v| u16::try_from(v).ok())
+    };
+    if let Some(iso) = as_u16(exif::TAG_ISO_SPEED) {
+        set_value(
+            props,
+            &photo_key(exif::TAG_ISO_SPEED),
+            &PROPVARIANT::from(iso),
+        )?;
+    }
+    if let Some(mode) = as_u16(exif::TAG_METERING_MODE) {
+        set_value(
+            props,
+            &photo_key(exif::TAG_METERING_MODE),
+            &PROPVARIANT::from(mode),
+        )?;
+    }
+    if let Some(length) = as_u16(exif::TAG_FOCAL_LENGTH_IN_35MM_FILM) {
+        set_value(
+            props,
+            &PKEY_FOCAL_LENGTH_IN_FILM,
+            &PROPVARIANT::from(length),
+        )?;
+    }
+    // System.Photo.Flash is a byte, which holds all the defined flash values.
+    if let Some(flash) = as_u16(exif::TAG_FLASH).and_then(|v| u8::try_from(v).ok()) {
+        set_value(
+            props,
+            &photo_key(exif::TAG_FLASH),
+            &PROPVARIANT::from(flash),
+        )?;
+    }
+
+    if let Some(file_time) = exif
+        .date_taken()
+        .and_then(|(date_time, offset)| to_file_time(date_time, offset))
+    {
+        let variant = unsafe { InitPropVariantFromFileTime(&file_time)? };
+        set_value(props, &photo_key(exif::TAG_DATE_TIME_ORIGINAL), &variant)?;
+    }
+
+    Ok(())
+}
+
+/// Publishes the location from the GPS IFD as System.GPS properties. Coordinates that are
+/// malformed or out of range are left out.
+fn set_gps_properties(props: &IPropertyStoreCache, exif: &Exif) -> windows::core::Result<()> {
+    let coordinates = [
+        (
+            exif.gps_latitude(),
+            FMTID_GPS_LATITUDE,
+            FMTID_GPS_LATITUDE_REF,
+            FMTID_GPS_LATITUDE_DECIMAL,
+        ),
+        (
+            exif.gps_longitude(),
+            FMTID_GPS_LONGITUDE,
+            FMTID_GPS_LONGITUDE_REF,
+            FMTID_GPS_LONGITUDE_DECIMAL,
+        ),
+    ];
+    for (coordinate, key, ref_key, decimal_key) in coordinates {
+        let Some(coordinate) = coordinate else {
+            continue;
+        };
+        let variant = unsafe { InitPropVariantFromDoubleVector(Some(&coordinate.dms))? };
+        set_value(props, &gps_key(key), &variant)?;
+        set_string(props, &gps_key(ref_key), coordinate.reference)?;
+        set_value(
+            props,
+            &gps_key(decimal_key),
+            &PROPVARIANT::from(coordinate.decimal()),
+        )?;
+    }
+
+    if let Some((altitude, below_sea_level)) = exif.gps_altitude() {
+        set_value(
+            props,
+            &gps_key(FMTID_GPS_ALTITUDE),
+            &PROPVARIANT::from(altitude),
+        )?;
+        set_value(
+            props,
+            &gps_key(FMTID_GPS_ALTITUDE_REF),
+            &PROPVARIANT::from(below_sea_level as u8),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Converts an EXIF date to UTC. Without a recorded offset it is taken as local time, as
+/// the Windows photo property handlers do.
+fn to_file_time(date_time: DateTime, offset: Option<i32>) -> Option<FILETIME> {
+    let local = SYSTEMTIME {
+        wYear: date_time.year,
+        wMonth: date_time.month,
+        wDayOfWeek: 0,
+        wDay: date_time.day,
+        wHour: date_time.hour,
+        wMinute: date_time.minute,
+        wSecond: date_time.second,
+        wMilliseconds: 0,
+    };
+    let mut file_time = FILETIME::default();
+    let Some(offset) = offset else {
+        let mut utc = SYSTEMTIME::default();
+        unsafe { TzSpecificLocalTimeToSystemTime(None, &local, &mut utc) }.ok()?;
+        unsafe { SystemTimeToFileTime(&utc, &mut file_time) }.ok()?;
+        return Some(file_time);
+    };
+
+    unsafe { SystemTimeToFileTime(&local, &mut file_time) }.ok()?;
+    // In 100 nanoseconds
+    let ticks = ((file_time.dwHighDateTime as u64) << 32) | file_time.dwLowDateTime as u64;
+    let ticks = ticks.checked_add_signed(-(offset as i64) * 60 * 10_000_000)?;
+    Some(FILETIME {
+        dwLowDateTime: ticks as u32,
+        dwHighDateTime: (ticks >> 32) as u32,
+    })
+}
+
+/// Whether the file holds the data to reconstruct the JPEG it was recompressed from. libjxl
+/// fails to reconstruct it if the XMP changes size, so such files are kept read-only. The
+/// stream is left at its start.
+fn has_jpeg_reconstruction(stream: &mut WinStream) -> windows::core::Result<bool> {
+    let found = container::has_box(&mut *stream, b"jbrd");
+    stream
+        .rewind()
+        .and(found)
+        .map_err(|err| windows::core::Error::new(STG_E_READFAULT, format!("{:?}", err)))
+}
+
+/// Reads the whole file again and returns it with the XMP edited.
+fn rewrite(stream: &IStream, edits: &[(&str, Option<XmpValue>)]) -> windows::core::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut reader = WinStream::from(stream);
+    reader
+        .rewind()
+        .and_then(|_| reader.read_to_end(&mut data))
+        .map_err(|err| windows::core::Error::new(STG_E_READFAULT, format!("{:?}", err)))?;
+
+    let settings = Settings::load();
+    // No time limit, as giving up would lose the edit.
+    let budget = Budget::new(0, Default::default());
+    let LoadedImage { image, incomplete } = loader::read_bytes(&data, &budget, &settings.limits)?;
+    if incomplete {
+        return Err(windows::core::Error::new(
+            WINCODEC_ERR_BADIMAGE,
+            "The image is truncated",
+        ));
+    }
+    if container::has_box(std::io::Cursor::new(&data), b"jbrd").unwrap_or(true) {
+        return Err(windows::core::Error::new(
+            STG_E_ACCESSDENIED,
+            "The file has JPEG reconstruction data",
+        ));
+    }
+    // jxl-oxide has already decompressed a brob box.
+    let original = match image.aux_boxes().first_xml() {
+        AuxBoxData::Data(data) => Some(data),
+        AuxBoxData::NotFound => None,
+        _ => {
+            return Err(windows::core::Error::new(
+                WINCODEC_ERR_BADMETADATAHEADER,
+                "The XMP box couldn't be read",
+            ));
+        }
+    };
+
+    let Some(xml) = xmp::update(original, edits) else {
+        return Err(windows::core::Error::new(
+            WINCODEC_ERR_BADMETADATAHEADER,
+            "The XMP packet couldn't be updated",
+        ));
+    };
+    container::replace_xml(&data, &xml).ok_or_else(|| {
+        windows::core::Error::new(WINCODEC_ERR_BADIMAGE, "The container is malformed")
+    })
+}
+
 impl IPropertyStore_Impl for JXLPropertyStore_Impl {
     fn GetCount(&self) -> windows::core::Result<u32> {
         unsafe { self.get_props()?.GetCount() }
@@ -125,28 +618,93 @@ impl IPropertyStore_Impl for JXLPropertyStore_Impl {
 
     fn SetValue(
         &self,
-        _key: *const PROPERTYKEY,
-        _propvar: *const PROPVARIANT,
+        key: *const PROPERTYKEY,
+        propvar: *const PROPVARIANT,
     ) -> windows::core::Result<()> {
-        Err(windows::core::Error::new(
-            WINCODEC_ERR_UNSUPPORTEDOPERATION,
-            "Setter not supported",
-        ))
+        let props = self.get_props()?;
+        let (Some(key), Some(variant)) = (unsafe { key.as_ref() }, unsafe { propvar.as_ref() })
+        else {
+            return Err(E_POINTER.into());
+        };
+        let Some(name) = xmp_name(key).filter(|_| self.stream.borrow().is_some()) else {
+            return Err(windows::core::Error::new(
+                STG_E_ACCESSDENIED,
+                "The property is read-only",
+            ));
+        };
+        // Fail on a value of the wrong type now rather than on commit.
+        propvariant_to_xmp(name, variant)?;
+        unsafe { props.SetValueAndState(key, variant, PSC_DIRTY) }
     }
 
     fn Commit(&self) -> windows::core::Result<()> {
-        Err(windows::core::Error::new(
-            WINCODEC_ERR_UNSUPPORTEDOPERATION,
-            "Setter not supported",
-        ))
+        let props = self.get_props()?;
+        let Some(stream) = self.stream.borrow().clone() else {
+            return Err(windows::core::Error::new(
+                STG_E_ACCESSDENIED,
+                "The stream is not opened for writing",
+            ));
+        };
+
+        let mut keys = vec![];
+        let mut edits = vec![];
+        for index in 0..unsafe { props.GetCount()? } {
+            let mut key = PROPERTYKEY::default();
+            unsafe { props.GetAt(index, &mut key)? };
+            let Some(name) = xmp_name(&key) else {
+                continue;
+            };
+            if unsafe { props.GetState(&key)? } != PSC_DIRTY {
+                continue;
+            }
+            let variant = unsafe { props.GetValue(&key)? };
+            edits.push((name, propvariant_to_xmp(name, &variant)?));
+            keys.push(key);
+        }
+        log::trace!("JXLPropertyStore::Commit: {:?}", edits);
+        if edits.is_empty() {
+            return Ok(());
+        }
+
+        let data = rewrite(&stream, &edits)?;
+
+        // The property system registers the handler with ManualSafeSave, and hands out a
+        // temporary stream that replaces the file only once committed. A failed write
+        // leaves the original untouched.
+        let factory: IDestinationStreamFactory = stream.cast()?;
+        let destination = unsafe { factory.GetDestinationStream()? };
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let len = rest.len().min(u32::MAX as usize) as u32;
+            let mut written = 0u32;
+            unsafe { destination.Write(rest.as_ptr() as _, len, Some(&mut written as *mut _)) }
+                .ok()?;
+            if written == 0 {
+                return Err(windows::core::Error::new(
+                    STG_E_WRITEFAULT,
+                    "IStream::Write wrote nothing",
+                ));
+            }
+            rest = &rest[written as usize..];
+        }
+        unsafe { destination.Commit(STGC_DEFAULT)? };
+
+        for key in keys {
+            unsafe { props.SetState(&key, PSC_NORMAL)? };
+        }
+        Ok(())
     }
 }
 
 impl IPropertyStoreCapabilities_Impl for JXLPropertyStore_Impl {
-    fn IsPropertyWritable(&self, _key: *const PROPERTYKEY) -> windows::core::Result<()> {
-        Err(windows::core::Error::new(
-            WINCODEC_ERR_UNSUPPORTEDOPERATION,
-            "Setter not supported",
-        ))
+    fn IsPropertyWritable(&self, key: *const PROPERTYKEY) -> windows::core::Result<()> {
+        let Some(key) = (unsafe { key.as_ref() }) else {
+            return Err(E_POINTER.into());
+        };
+        // Nothing is writable unless the store was opened with STGM_READWRITE.
+        match xmp_name(key) {
+            Some(_) if self.stream.borrow().is_some() => Ok(()),
+            _ => Err(S_FALSE.into()),
+        }
     }
 }
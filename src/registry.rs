@@ -70,9 +70,9 @@ fn register_clsid(module_path: &str) -> std::io::Result<()> {
     wic_decoder_key.set_value("FileExtensions", &EXT)?;
 
     let (formats, _) = wic_decoder_key.create_subkey("Formats")?;
-    formats.create_subkey(guid_to_string(
-        &windows::Win32::Graphics::Imaging::GUID_WICPixelFormat32bppRGBA,
-    ))?;
+    for format in JXLWICBitmapDecoder::PIXEL_FORMATS {
+        formats.create_subkey(guid_to_string(format))?;
+    }
 
     // Decoder specific required entries
     // https://docs.microsoft.com/en-us/windows/win32/wic/-wic-decoderregentries
@@ -125,8 +125,8 @@ fn register_property_list(system_ext_key: &RegKey) -> std::io::Result<()> {
     // The example uses HKCR\.ext but somehow the system actually uses HKCR\SystemFileAssociations\.ext instead.
 
     // Copied from other system file associations and trimmed down.
-    system_ext_key.set_value("FullDetails", &"prop:System.PropGroup.Image;System.Image.Dimensions;System.Image.HorizontalSize;System.Image.VerticalSize;System.PropGroup.FileSystem;System.ItemNameDisplay;System.ItemType;System.ItemFolderPathDisplay;System.DateCreated;System.DateModified;System.Size;System.FileAttributes;System.OfflineAvailability;System.OfflineStatus;System.SharedWith;System.FileOwner;System.ComputerName")?;
-    system_ext_key.set_value("PreviewDetails", &"prop:*System.Image.Dimensions;*System.Size;*System.OfflineAvailability;*System.OfflineStatus;*System.DateCreated;*System.DateModified;*System.DateAccessed;*System.SharedWith")?;
+    system_ext_key.set_value("FullDetails", &"prop:System.PropGroup.Description;System.Title;System.Rating;System.Keywords;System.Comment;System.PropGroup.Origin;System.Author;System.Photo.DateTaken;System.PropGroup.Image;System.Image.Dimensions;System.Image.HorizontalSize;System.Image.VerticalSize;System.Image.BitDepth;System.Image.ColorSpace;JxlWinthumb.Image.HasAlpha;System.PropGroup.Camera;System.Photo.CameraManufacturer;System.Photo.CameraModel;System.Photo.FNumber;System.Photo.ExposureTime;System.Photo.ISOSpeed;System.Photo.ExposureBias;System.Photo.FocalLength;System.Photo.MeteringMode;System.Photo.Flash;System.Photo.FocalLengthInFilm;System.PropGroup.GPS;System.GPS.Latitude;System.GPS.Longitude;System.GPS.Altitude;System.PropGroup.FileSystem;System.ItemNameDisplay;System.ItemType;System.ItemFolderPathDisplay;System.DateCreated;System.DateModified;System.Size;System.FileAttributes;System.OfflineAvailability;System.OfflineStatus;System.SharedWith;System.FileOwner;System.ComputerName")?;
+    system_ext_key.set_value("PreviewDetails", &"prop:*System.Title;*System.Rating;*System.Keywords;*System.Photo.DateTaken;*System.Photo.CameraModel;*System.Image.Dimensions;*System.Image.BitDepth;*JxlWinthumb.Image.HasAlpha;*System.Size;*System.OfflineAvailability;*System.OfflineStatus;*System.DateCreated;*System.DateModified;*System.DateAccessed;*System.SharedWith")?;
     Ok(())
 }
 
@@ -211,13 +211,15 @@ pub fn register(module_path: &str) -> std::io::Result<()> {
     register_provider()?;
     kindmap::register_explorer_kind()?;
     property_handler::register_property_handler(module_path)?;
+    property_handler::register_property_schema(module_path)?;
     Ok(())
 }
 
-pub fn unregister() -> std::io::Result<()> {
+pub fn unregister(module_path: &str) -> std::io::Result<()> {
     unregister_clsid();
     unregister_provider()?;
     kindmap::unregister_explorer_kind().ok();
     property_handler::unregister_property_handler().ok();
+    property_handler::unregister_property_schema(module_path).ok();
     Ok(())
 }
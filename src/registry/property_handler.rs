@@ -1,3 +1,9 @@
+use std::path::{Path, PathBuf};
+
+use windows::Win32::UI::Shell::PropertiesSystem::{
+    PSRegisterPropertySchema, PSUnregisterPropertySchema,
+};
+use windows::core::HSTRING;
 use winreg::RegKey;
 use winreg::enums::*;
 
@@ -9,11 +15,41 @@ use super::{EXT, register_clsid_base};
 const PROPERTY_HANDLERS_KEY: &str =
     "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\PropertySystem\\PropertyHandlers";
 
+/// Describes the properties that are ours, like PKEY_HAS_ALPHA, so that Explorer can show
+/// them. The property system reads the file from where it is registered from then on.
+const SCHEMA: &str = include_str!("jxl-winthumb.propdesc");
+const SCHEMA_FILE: &str = "jxl-winthumb.propdesc";
+
+/// The schema goes next to the dll.
+fn schema_path(module_path: &str) -> std::io::Result<PathBuf> {
+    Path::new(module_path)
+        .parent()
+        .map(|dir| dir.join(SCHEMA_FILE))
+        .ok_or_else(|| std::io::Error::other("The dll path has no directory"))
+}
+
+pub fn register_property_schema(module_path: &str) -> std::io::Result<()> {
+    // https://learn.microsoft.com/en-us/windows/win32/properties/propdesc-schema-entry
+    let path = schema_path(module_path)?;
+    std::fs::write(&path, SCHEMA)?;
+    unsafe { PSRegisterPropertySchema(&HSTRING::from(path.as_os_str())) }
+        .map_err(std::io::Error::other)
+}
+
+pub fn unregister_property_schema(module_path: &str) -> std::io::Result<()> {
+    let path = schema_path(module_path)?;
+    unsafe { PSUnregisterPropertySchema(&HSTRING::from(path.as_os_str())) }
+        .map_err(std::io::Error::other)?;
+    std::fs::remove_file(&path)
+}
+
 pub fn register_property_handler(module_path: &str) -> std::io::Result<()> {
     // https://docs.microsoft.com/en-us/windows/win32/properties/prophand-reg-dist
 
-    // No ManualSafeSave needed since it's currently read-only
-    register_clsid_base(module_path, &JXLPropertyStore::CLSID)?;
+    // Commit writes to the stream of IDestinationStreamFactory, which the property system
+    // only provides with ManualSafeSave.
+    let clsid_key = register_clsid_base(module_path, &JXLPropertyStore::CLSID)?;
+    clsid_key.set_value("ManualSafeSave", &1u32)?;
 
     let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
     let handlers_key = hklm.open_subkey(PROPERTY_HANDLERS_KEY)?;
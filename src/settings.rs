@@ -0,0 +1,110 @@
+use winreg::RegKey;
+use winreg::enums::*;
+use winreg::types::FromRegValue;
+
+use crate::color::TargetColorSpace;
+use crate::limits::Limits;
+use crate::tonemap::ToneMapping;
+
+// Per-user values under HKCU take precedence over per-machine values under HKLM.
+const SETTINGS_KEY: &str = "SOFTWARE\\jxl-winthumb";
+
+const THUMBNAIL_MAX_SIZE: &str = "ThumbnailMaxSize";
+const FRAME_CACHE_SIZE: &str = "FrameCacheSize";
+const TONE_MAPPING: &str = "ToneMapping";
+const DITHER: &str = "Dither";
+const COLOR_SPACE: &str = "ColorSpace";
+const DECODE_TIMEOUT: &str = "DecodeTimeout";
+const MAX_PIXELS: &str = "MaxPixels";
+const MAX_FRAMES: &str = "MaxFrames";
+// In MiB
+const MAX_MEMORY: &str = "MaxMemory";
+const MAX_ICC_SIZE: &str = "MaxIccSize";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    /// The maximum length of the longest side of images returned by GetThumbnail.
+    pub thumbnail_max_size: u32,
+    /// The number of rendered frames kept per decoder. 0 disables the cache.
+    pub frame_cache_size: u32,
+    /// How HDR images are mapped to SDR when an integer format is requested.
+    pub tone_mapping: ToneMapping,
+    /// Whether to dither when writing 8 bit formats.
+    pub dither: bool,
+    /// The color space SDR images are rendered in.
+    pub color_space: TargetColorSpace,
+    /// How long loading the image, or rendering a region of it, may take in milliseconds.
+    /// 0 has no limit.
+    pub decode_timeout: u32,
+    pub limits: Limits,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            thumbnail_max_size: 256,
+            frame_cache_size: 4,
+            tone_mapping: ToneMapping::Bt2408,
+            dither: false,
+            color_space: TargetColorSpace::Original,
+            decode_timeout: 10_000,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn load() -> Self {
+        let mut settings = Self::default();
+        if let Some(size) = read_value::<u32>(THUMBNAIL_MAX_SIZE) {
+            settings.thumbnail_max_size = size.max(1);
+        }
+        if let Some(size) = read_value::<u32>(FRAME_CACHE_SIZE) {
+            settings.frame_cache_size = size;
+        }
+        if let Some(name) = read_value::<String>(TONE_MAPPING) {
+            match ToneMapping::from_name(&name) {
+                Some(tone_mapping) => settings.tone_mapping = tone_mapping,
+                None => log::trace!("Settings::load: unknown tone mapping {}", name),
+            }
+        }
+        if let Some(dither) = read_value::<u32>(DITHER) {
+            settings.dither = dither != 0;
+        }
+        if let Some(name) = read_value::<String>(COLOR_SPACE) {
+            match TargetColorSpace::from_name(&name) {
+                Some(color_space) => settings.color_space = color_space,
+                None => log::trace!("Settings::load: unknown color space {}", name),
+            }
+        }
+        if let Some(timeout) = read_value::<u32>(DECODE_TIMEOUT) {
+            settings.decode_timeout = timeout;
+        }
+        if let Some(pixels) = read_value::<u32>(MAX_PIXELS) {
+            settings.limits.max_pixels = pixels as u64;
+        }
+        if let Some(frames) = read_value::<u32>(MAX_FRAMES) {
+            settings.limits.max_frames = frames;
+        }
+        if let Some(mebibytes) = read_value::<u32>(MAX_MEMORY) {
+            settings.limits.max_memory = mebibytes as u64 * 1024 * 1024;
+        }
+        if let Some(size) = read_value::<u32>(MAX_ICC_SIZE) {
+            settings.limits.max_icc_size = size;
+        }
+        log::trace!("Settings::load {:?}", settings);
+        settings
+    }
+}
+
+fn read_value<T: FromRegValue>(name: &str) -> Option<T> {
+    [HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE]
+        .into_iter()
+        .find_map(|predef| {
+            RegKey::predef(predef)
+                .open_subkey(SETTINGS_KEY)
+                .ok()?
+                .get_value(name)
+                .ok()
+        })
+}
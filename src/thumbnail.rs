@@ -0,0 +1,156 @@
+use windows::Win32::Graphics::Imaging::WICRect;
+
+use crate::{FrameBuffer, hdr};
+
+/// Fits the given size into `max_size` x `max_size` while keeping the aspect ratio.
+pub fn thumbnail_size(width: u32, height: u32, max_size: u32) -> (u32, u32) {
+    let longest = width.max(height);
+    if longest <= max_size {
+        return (width, height);
+    }
+    let scale = |v: u32| ((v as u64 * max_size as u64 / longest as u64) as u32).max(1);
+    (scale(width), scale(height))
+}
+
+/// Downscales a frame with a box filter, a piece at a time. Each destination pixel is
+/// the average of the source pixels it covers, so huge frames can be scaled tile by tile
+/// without rendering the whole frame at once.
+pub struct Downscaler {
+    width: usize,
+    height: usize,
+    dst_width: usize,
+    dst_height: usize,
+    /// The part of the scaled frame that is accumulated
+    rect: WICRect,
+    channels: usize,
+    /// Whether the samples are half float bits
+    half: bool,
+    sums: Vec<f64>,
+}
+
+impl Downscaler {
+    /// Scales `width` x `height` down to `dst_width` x `dst_height`, which must not be
+    /// larger, and keeps `rect` of the result.
+    pub fn new(
+        width: u32,
+        height: u32,
+        dst_width: u32,
+        dst_height: u32,
+        rect: &WICRect,
+        channels: usize,
+        half: bool,
+    ) -> Self {
+        debug_assert!(dst_width <= width && dst_height <= height);
+        Self {
+            width: width as usize,
+            height: height as usize,
+            dst_width: dst_width as usize,
+            dst_height: dst_height as usize,
+            rect: *rect,
+            channels,
+            half,
+            sums: vec![0f64; rect.Width as usize * rect.Height as usize * channels],
+        }
+    }
+
+    /// The first source column or row of the destination column or row `dst`.
+    fn start(dst: usize, len: usize, dst_len: usize) -> usize {
+        dst * len / dst_len
+    }
+
+    /// The destination column or row that the source column or row `src` falls in.
+    fn target(src: usize, len: usize, dst_len: usize) -> usize {
+        ((src + 1) * dst_len - 1) / len
+    }
+
+    /// The part of the source that `rect` is averaged from.
+    pub fn source_rect(&self) -> WICRect {
+        let left = Self::start(self.rect.X as usize, self.width, self.dst_width);
+        let top = Self::start(self.rect.Y as usize, self.height, self.dst_height);
+        let right = Self::start(
+            (self.rect.X + self.rect.Width) as usize,
+            self.width,
+            self.dst_width,
+        );
+        let bottom = Self::start(
+            (self.rect.Y + self.rect.Height) as usize,
+            self.height,
+            self.dst_height,
+        );
+        WICRect {
+            X: left as i32,
+            Y: top as i32,
+            Width: (right - left) as i32,
+            Height: (bottom - top) as i32,
+        }
+    }
+
+    /// Adds the pixels of `src`, which covers `src_rect` of the source.
+    pub fn add(&mut self, src: &FrameBuffer, src_rect: &WICRect) {
+        let to_f64: fn(u16) -> f64 = if self.half {
+            hdr::half_to_f64
+        } else {
+            |v| v as f64
+        };
+        let source = self.source_rect();
+        let left = src_rect.X.max(source.X);
+        let top = src_rect.Y.max(source.Y);
+        let right = (src_rect.X + src_rect.Width).min(source.X + source.Width);
+        let bottom = (src_rect.Y + src_rect.Height).min(source.Y + source.Height);
+        if left >= right || top >= bottom {
+            return;
+        }
+        let (left, top, right, bottom) =
+            (left as usize, top as usize, right as usize, bottom as usize);
+        let channels = self.channels;
+        let dst_row_len = self.rect.Width as usize * channels;
+
+        for y in top..bottom {
+            let dy = Self::target(y, self.height, self.dst_height) - self.rect.Y as usize;
+            let src_offset = ((y - src_rect.Y as usize) * src_rect.Width as usize
+                + (left - src_rect.X as usize))
+                * channels;
+            let row = &src.buf[src_offset..src_offset + (right - left) * channels];
+            for (x, pixel) in (left..right).zip(row.chunks_exact(channels)) {
+                let dx = Self::target(x, self.width, self.dst_width) - self.rect.X as usize;
+                let offset = dy * dst_row_len + dx * channels;
+                for (sum, &sample) in self.sums[offset..offset + channels].iter_mut().zip(pixel) {
+                    *sum += to_f64(sample);
+                }
+            }
+        }
+    }
+
+    /// Averages the sums into the scaled pixels of `rect`.
+    pub fn finish(self) -> FrameBuffer {
+        let from_f64: fn(f64) -> u16 = if self.half {
+            hdr::f64_to_half
+        } else {
+            |v| v.round() as u16
+        };
+        let channels = self.channels;
+        let (rect_width, rect_height) = (self.rect.Width as usize, self.rect.Height as usize);
+        let mut dst = FrameBuffer::new(rect_width, rect_height, channels);
+
+        for y in 0..rect_height {
+            let dy = self.rect.Y as usize + y;
+            let rows = Self::start(dy + 1, self.height, self.dst_height)
+                - Self::start(dy, self.height, self.dst_height);
+            for x in 0..rect_width {
+                let dx = self.rect.X as usize + x;
+                let columns = Self::start(dx + 1, self.width, self.dst_width)
+                    - Self::start(dx, self.width, self.dst_width);
+                let count = (rows * columns) as f64;
+                let offset = (y * rect_width + x) * channels;
+                for (out, sum) in dst.buf[offset..offset + channels]
+                    .iter_mut()
+                    .zip(&self.sums[offset..offset + channels])
+                {
+                    *out = from_f64(sum / count);
+                }
+            }
+        }
+
+        dst
+    }
+}
@@ -0,0 +1,141 @@
+use windows::Win32::Graphics::Imaging::WICRect;
+
+use crate::FrameBuffer;
+
+/// The side length of the tiles that huge images are rendered in by default. This is a
+/// multiple of every JXL group size, so that a tile never renders a group only partially.
+pub const TILE_SIZE: u32 = 1024;
+
+/// The least number of tiles kept per decoder, which is about 128 MiB for 16 bit RGBA.
+/// Wider images keep two rows of tiles.
+pub const TILE_CACHE_SIZE: usize = 16;
+
+/// Whether the image is large enough to render in tiles of `tile_size`.
+pub fn is_tiled(width: u32, height: u32, tile_size: u32) -> bool {
+    width > tile_size || height > tile_size
+}
+
+/// The number of tiles of `tile_size` across `length` pixels.
+pub fn tiles_along(length: u32, tile_size: u32) -> usize {
+    length.div_ceil(tile_size) as usize
+}
+
+/// Returns the tile rectangles of the image that intersect with the given rectangle,
+/// along with the tile coordinates.
+pub fn tiles_in(
+    rect: &WICRect,
+    width: u32,
+    height: u32,
+    tile_size: u32,
+) -> Vec<((u32, u32), WICRect)> {
+    if rect.Width <= 0 || rect.Height <= 0 {
+        return vec![];
+    }
+    let first_x = rect.X as u32 / tile_size;
+    let first_y = rect.Y as u32 / tile_size;
+    let last_x = (rect.X + rect.Width - 1) as u32 / tile_size;
+    let last_y = (rect.Y + rect.Height - 1) as u32 / tile_size;
+
+    let mut tiles = vec![];
+    for ty in first_y..=last_y {
+        for tx in first_x..=last_x {
+            let x = tx * tile_size;
+            let y = ty * tile_size;
+            let tile = WICRect {
+                X: x as i32,
+                Y: y as i32,
+                Width: tile_size.min(width - x) as i32,
+                Height: tile_size.min(height - y) as i32,
+            };
+            tiles.push(((tx, ty), tile));
+        }
+    }
+    tiles
+}
+
+/// Copies the part of the tile that overlaps with `rect` into `dst`, which covers `rect`.
+pub fn paste(tile: &FrameBuffer, tile_rect: &WICRect, dst: &mut FrameBuffer, rect: &WICRect) {
+    let channels = dst.channels;
+    let left = tile_rect.X.max(rect.X);
+    let top = tile_rect.Y.max(rect.Y);
+    let right = (tile_rect.X + tile_rect.Width).min(rect.X + rect.Width);
+    let bottom = (tile_rect.Y + tile_rect.Height).min(rect.Y + rect.Height);
+    if left >= right || top >= bottom {
+        return;
+    }
+    let row_len = (right - left) as usize * channels;
+
+    for y in top..bottom {
+        let src_offset = ((y - tile_rect.Y) as usize * tile_rect.Width as usize
+            + (left - tile_rect.X) as usize)
+            * channels;
+        let dst_offset =
+            ((y - rect.Y) as usize * rect.Width as usize + (left - rect.X) as usize) * channels;
+        dst.buf[dst_offset..dst_offset + row_len]
+            .copy_from_slice(&tile.buf[src_offset..src_offset + row_len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 2500;
+    const HEIGHT: u32 = 1300;
+
+    /// A frame whose pixels all differ, standing in for a full render.
+    fn frame() -> FrameBuffer {
+        let mut frame = FrameBuffer::new(WIDTH as usize, HEIGHT as usize, 3);
+        for (i, sample) in frame.buf.iter_mut().enumerate() {
+            *sample = (i % 65521) as u16;
+        }
+        frame
+    }
+
+    fn crop(frame: &FrameBuffer, rect: &WICRect) -> FrameBuffer {
+        let mut dst = FrameBuffer::new(rect.Width as usize, rect.Height as usize, 3);
+        paste(
+            frame,
+            &WICRect {
+                X: 0,
+                Y: 0,
+                Width: WIDTH as i32,
+                Height: HEIGHT as i32,
+            },
+            &mut dst,
+            rect,
+        );
+        dst
+    }
+
+    #[test]
+    fn tiles_cross_boundaries() {
+        let full = frame();
+        let rect = WICRect {
+            X: 1000,
+            Y: 1000,
+            Width: 1100,
+            Height: 200,
+        };
+        let tiles = tiles_in(&rect, WIDTH, HEIGHT, TILE_SIZE);
+        let positions: Vec<_> = tiles.iter().map(|(position, _)| *position).collect();
+        assert_eq!(positions, [(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (2, 1)]);
+        // The last column and row are cut at the edges of the image.
+        assert_eq!(tiles[5].1.Width, 452);
+        assert_eq!(tiles[5].1.Height, 276);
+
+        let mut region = FrameBuffer::new(rect.Width as usize, rect.Height as usize, 3);
+        for (_, tile_rect) in &tiles {
+            paste(&crop(&full, tile_rect), tile_rect, &mut region, &rect);
+        }
+        assert_eq!(region.buf, crop(&full, &rect).buf);
+    }
+
+    #[test]
+    fn tiles_along_edges() {
+        assert_eq!(tiles_along(1024, TILE_SIZE), 1);
+        assert_eq!(tiles_along(1025, TILE_SIZE), 2);
+        assert_eq!(tiles_along(WIDTH, TILE_SIZE), 3);
+        assert!(tiles_in(&WICRect::default(), WIDTH, HEIGHT, TILE_SIZE).is_empty());
+    }
+}
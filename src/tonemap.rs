@@ -0,0 +1,123 @@
+// Maps scRGB HDR frames into SDR for consumers that can't take float formats.
+// The HDR reference white of 203 nits from BT.2408 becomes SDR white.
+// https://www.itu.int/pub/R-REP-BT.2408
+// https://www.itu.int/pub/R-REP-BT.2390
+
+use crate::FrameBuffer;
+use crate::hdr::{self, SCRGB_WHITE_NITS};
+
+pub const REFERENCE_WHITE_NITS: f32 = 203.0;
+
+// SMPTE ST 2084 constants
+const M1: f32 = 0.1593017578125;
+const M2: f32 = 78.84375;
+const C1: f32 = 0.8359375;
+const C2: f32 = 18.8515625;
+const C3: f32 = 18.6875;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapping {
+    /// The EETF from BT.2408 Annex 5, a hermite spline roll-off in the PQ domain.
+    Bt2408,
+    /// The extended Reinhard operator, which maps the peak luminance to white.
+    Reinhard,
+    /// Clips everything brighter than the reference white.
+    Clip,
+}
+
+impl ToneMapping {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "bt2408" => Some(ToneMapping::Bt2408),
+            "reinhard" => Some(ToneMapping::Reinhard),
+            "clip" => Some(ToneMapping::Clip),
+            _ => None,
+        }
+    }
+}
+
+/// SMPTE ST 2084, from nits to the signal.
+fn pq_encode(nits: f32) -> f32 {
+    let y = (nits / 10000.0).max(0.0).powf(M1);
+    ((C1 + C2 * y) / (1.0 + C3 * y)).powf(M2)
+}
+
+/// SMPTE ST 2084, from the signal to nits.
+fn pq_decode(signal: f32) -> f32 {
+    let e = signal.max(0.0).powf(1.0 / M2);
+    10000.0 * ((e - C1).max(0.0) / (C2 - C3 * e)).powf(1.0 / M1)
+}
+
+/// Maps `nits` in `0..=peak` into `0..=REFERENCE_WHITE_NITS`.
+fn bt2408(nits: f32, peak: f32) -> f32 {
+    let source_peak = pq_encode(peak);
+    let e1 = pq_encode(nits) / source_peak;
+    let max_lum = pq_encode(REFERENCE_WHITE_NITS) / source_peak;
+    let knee = 1.5 * max_lum - 0.5;
+
+    // No roll-off is needed when the peak is already in the SDR range.
+    let e2 = if e1 < knee || knee >= 1.0 {
+        e1
+    } else {
+        let t = (e1 - knee) / (1.0 - knee);
+        let (t2, t3) = (t * t, t * t * t);
+        (2.0 * t3 - 3.0 * t2 + 1.0) * knee
+            + (t3 - 2.0 * t2 + t) * (1.0 - knee)
+            + (-2.0 * t3 + 3.0 * t2) * max_lum
+    };
+    pq_decode(e2 * source_peak)
+}
+
+/// The sRGB transfer function.
+fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Converts an scRGB RGBA half float frame to a 16 bit sRGB RGBA frame. `peak` is the
+/// intensity target of the image in nits.
+pub fn tone_map(src: &FrameBuffer, peak: f32, operator: ToneMapping) -> FrameBuffer {
+    debug_assert_eq!(src.channels, 4);
+    let peak = peak.max(REFERENCE_WHITE_NITS);
+    // Relative to the reference white
+    let relative_peak = peak / REFERENCE_WHITE_NITS;
+
+    // Maps the brightest channel in nits to the SDR range.
+    let map = |nits: f32| -> f32 {
+        match operator {
+            ToneMapping::Bt2408 => bt2408(nits.min(peak), peak) / REFERENCE_WHITE_NITS,
+            ToneMapping::Reinhard => {
+                let l = nits / REFERENCE_WHITE_NITS;
+                l * (1.0 + l / (relative_peak * relative_peak)) / (1.0 + l)
+            }
+            ToneMapping::Clip => nits / REFERENCE_WHITE_NITS,
+        }
+    };
+    let to_u16 = |v: f32| (srgb_encode(v.clamp(0.0, 1.0)) * 65535.0).round() as u16;
+
+    let buf = src
+        .buf
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let [r, g, b, a] = [0, 1, 2, 3].map(|i| hdr::half_to_f64(pixel[i]) as f32);
+            let [r, g, b] = [r, g, b].map(|v| v.max(0.0) * SCRGB_WHITE_NITS);
+            // Scaling the channels together keeps the hue.
+            let max_nits = r.max(g).max(b);
+            let scale = if max_nits > 0.0 {
+                map(max_nits) / max_nits
+            } else {
+                0.0
+            };
+            [
+                to_u16(r * scale),
+                to_u16(g * scale),
+                to_u16(b * scale),
+                (a.clamp(0.0, 1.0) * 65535.0).round() as u16,
+            ]
+        })
+        .collect();
+    FrameBuffer { channels: 4, buf }
+}
@@ -0,0 +1,108 @@
+use windows::Win32::{
+    Foundation::E_INVALIDARG,
+    Graphics::Imaging::{
+        WICBitmapTransformFlipHorizontal, WICBitmapTransformFlipVertical,
+        WICBitmapTransformOptions, WICRect,
+    },
+};
+
+use crate::FrameBuffer;
+
+/// Validates the rectangle passed to CopyPixels. A null rectangle means the whole frame.
+pub fn resolve_rect(
+    prc: *const WICRect,
+    width: u32,
+    height: u32,
+) -> windows::core::Result<WICRect> {
+    let Some(rect) = (unsafe { prc.as_ref() }) else {
+        return Ok(WICRect {
+            X: 0,
+            Y: 0,
+            Width: width as i32,
+            Height: height as i32,
+        });
+    };
+
+    if rect.X < 0
+        || rect.Y < 0
+        || rect.Width < 0
+        || rect.Height < 0
+        || rect.X as u32 + rect.Width as u32 > width
+        || rect.Y as u32 + rect.Height as u32 > height
+    {
+        return Err(windows::core::Error::new(
+            E_INVALIDARG,
+            format!("{:?} is outside of the {}x{} frame", rect, width, height),
+        ));
+    }
+
+    Ok(*rect)
+}
+
+/// Copies the given rectangle out of the frame. The rectangle must be inside the frame.
+pub fn crop(src: &FrameBuffer, width: u32, rect: &WICRect) -> FrameBuffer {
+    let channels = src.channels;
+    let mut dst = FrameBuffer::new(rect.Width as usize, rect.Height as usize, channels);
+    let row_len = rect.Width as usize * channels;
+    if row_len == 0 {
+        return dst;
+    }
+
+    for (y, dst_row) in dst.buf.chunks_exact_mut(row_len).enumerate() {
+        let src_offset = ((rect.Y as usize + y) * width as usize + rect.X as usize) * channels;
+        dst_row.copy_from_slice(&src.buf[src_offset..src_offset + row_len]);
+    }
+
+    dst
+}
+
+/// Rotates the frame clockwise and then flips it, as WIC applies the transform options.
+/// Returns the transformed frame with its new width and height.
+pub fn rotate_flip(
+    src: &FrameBuffer,
+    width: u32,
+    height: u32,
+    options: WICBitmapTransformOptions,
+) -> (FrameBuffer, u32, u32) {
+    let quarter_turns = options.0 & 0x3;
+    let flip_horizontal = options.0 & WICBitmapTransformFlipHorizontal.0 != 0;
+    let flip_vertical = options.0 & WICBitmapTransformFlipVertical.0 != 0;
+
+    if quarter_turns == 0 && !flip_horizontal && !flip_vertical {
+        return (src.clone(), width, height);
+    }
+
+    let (width, height) = (width as usize, height as usize);
+    let (dst_width, dst_height) = if quarter_turns % 2 == 1 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let channels = src.channels;
+    let mut dst = FrameBuffer::new(dst_width, dst_height, channels);
+
+    for y in 0..dst_height {
+        for x in 0..dst_width {
+            // Undo the flip first, then map the rotated position back to the source.
+            let rotated_x = if flip_horizontal {
+                dst_width - 1 - x
+            } else {
+                x
+            };
+            let rotated_y = if flip_vertical { dst_height - 1 - y } else { y };
+            let (src_x, src_y) = match quarter_turns {
+                0 => (rotated_x, rotated_y),
+                1 => (rotated_y, height - 1 - rotated_x),
+                2 => (width - 1 - rotated_x, height - 1 - rotated_y),
+                _ => (width - 1 - rotated_y, rotated_x),
+            };
+
+            let src_offset = (src_y * width + src_x) * channels;
+            let dst_offset = (y * dst_width + x) * channels;
+            dst.buf[dst_offset..dst_offset + channels]
+                .copy_from_slice(&src.buf[src_offset..src_offset + channels]);
+        }
+    }
+
+    (dst, dst_width as u32, dst_height as u32)
+}
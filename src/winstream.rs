@@ -1,14 +1,61 @@
-use std::io::Read;
+use std::ffi::OsString;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::windows::ffi::OsStringExt;
+use std::path::PathBuf;
 
-use windows::Win32::System::Com::IStream;
+use windows::Win32::System::Com::{
+    CoTaskMemFree, IStream, STATFLAG_DEFAULT, STATFLAG_NONAME, STATSTG, STGTY_STREAM,
+    STREAM_SEEK_CUR, STREAM_SEEK_END, STREAM_SEEK_SET,
+};
 
+/// Reads an IStream from where it was positioned when wrapped. Seek positions and the size
+/// are relative to that start, as the image may be embedded in a larger stream.
 pub struct WinStream<'a> {
     stream: &'a IStream,
+    start: u64,
 }
 
 impl<'a> From<&'a IStream> for WinStream<'a> {
     fn from(stream: &'a IStream) -> Self {
-        Self { stream }
+        let mut start = 0u64;
+        if let Err(err) = unsafe { stream.Seek(0, STREAM_SEEK_CUR, Some((&mut start) as *mut _)) } {
+            log::trace!("WinStream::from: IStream::Seek failed: {:?}", err);
+        }
+        Self { stream, start }
+    }
+}
+
+impl WinStream<'_> {
+    /// The size from the start, regardless of the current position.
+    pub fn size(&self) -> Result<u64, std::io::Error> {
+        let mut stat = STATSTG::default();
+        unsafe { self.stream.Stat(&mut stat, STATFLAG_NONAME) }.map_err(|err| {
+            std::io::Error::other(format!("IStream::Stat failed: {}", err.code().0))
+        })?;
+        Ok(stat.cbSize.saturating_sub(self.start))
+    }
+
+    /// Where the image starts in the underlying stream.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The file behind a file-backed stream, like those from SHCreateStreamOnFileEx, and its
+    /// last write time in FILETIME ticks, which is 0 if the stream doesn't tell. Memory
+    /// streams have no name, and other streams may name something that is not a file path.
+    pub fn file(&self) -> Option<(PathBuf, u64)> {
+        let mut stat = STATSTG::default();
+        unsafe { self.stream.Stat(&mut stat, STATFLAG_DEFAULT) }.ok()?;
+        if stat.pwcsName.is_null() {
+            return None;
+        }
+        let name = OsString::from_wide(unsafe { stat.pwcsName.as_wide() });
+        unsafe { CoTaskMemFree(Some(stat.pwcsName.0 as _)) };
+        if stat.r#type != STGTY_STREAM.0 as u32 {
+            return None;
+        }
+        let modified = (stat.mtime.dwHighDateTime as u64) << 32 | stat.mtime.dwLowDateTime as u64;
+        Some((PathBuf::from(name), modified))
     }
 }
 
@@ -27,3 +74,41 @@ impl Read for WinStream<'_> {
         Ok(bytes_read as usize)
     }
 }
+
+impl Seek for WinStream<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, std::io::Error> {
+        let (offset, origin) = match pos {
+            SeekFrom::Start(offset) => {
+                let offset = self
+                    .start
+                    .checked_add(offset)
+                    .and_then(|offset| i64::try_from(offset).ok())
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "Seek past the largest stream position",
+                        )
+                    })?;
+                (offset, STREAM_SEEK_SET)
+            }
+            SeekFrom::Current(offset) => (offset, STREAM_SEEK_CUR),
+            SeekFrom::End(offset) => (offset, STREAM_SEEK_END),
+        };
+        let mut new_position = 0u64;
+        unsafe {
+            self.stream
+                .Seek(offset, origin, Some((&mut new_position) as *mut _))
+        }
+        .map_err(|err| std::io::Error::other(format!("IStream::Seek failed: {}", err.code().0)))?;
+        if new_position < self.start {
+            // Seeking before the start is an error for std::io::Seek, as it is for IStream
+            // at 0.
+            unsafe { self.stream.Seek(self.start as i64, STREAM_SEEK_SET, None) }.ok();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seek before the start of the stream",
+            ));
+        }
+        Ok(new_position - self.start)
+    }
+}
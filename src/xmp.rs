@@ -0,0 +1,715 @@
+// A small XMP reader that flattens the top level properties of rdf:Description.
+// Nested structures are skipped, as none of the properties we expose use them.
+// The writer replaces top level properties and keeps everything else as it was.
+// Names are matched by namespace, so a packet that binds the XMP namespace to `xap` works the
+// same as one that uses `xmp`.
+// https://developer.adobe.com/xmp/docs/XMPSpecifications/
+
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::name::{LocalName, Namespace, PrefixDeclaration, QName, ResolveResult};
+use quick_xml::{NsReader, Writer};
+
+#[derive(Debug, Clone)]
+pub enum XmpValue {
+    Text(String),
+    /// rdf:Bag or rdf:Seq
+    Array(Vec<String>),
+    /// rdf:Alt, as pairs of xml:lang and text
+    LangAlt(Vec<(String, String)>),
+}
+
+impl XmpValue {
+    /// Returns the simple value, the x-default (or first) alternative, or the first item.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            XmpValue::Text(text) => Some(text),
+            XmpValue::Array(items) => items.first().map(String::as_str),
+            XmpValue::LangAlt(items) => items
+                .iter()
+                .find(|(lang, _)| lang == "x-default")
+                .or(items.first())
+                .map(|(_, text)| text.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Xmp {
+    /// Pairs of qualified names like `dc:title` and their values, in document order. Names in
+    /// the namespaces of `NAMESPACES` use the prefix given there.
+    pub properties: Vec<(String, XmpValue)>,
+}
+
+impl Xmp {
+    pub fn get(&self, name: &str) -> Option<&XmpValue> {
+        self.properties
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+}
+
+struct Property {
+    name: String,
+    value: XmpValue,
+    // Element depth of the property element itself
+    depth: usize,
+}
+
+/// The namespaces that properties are known by, with the prefixes used for them here whatever
+/// prefix a packet binds them to.
+const NAMESPACES: [(&str, &str); 8] = [
+    ("dc", "http://purl.org/dc/elements/1.1/"),
+    ("xmp", "http://ns.adobe.com/xap/1.0/"),
+    ("xmpRights", "http://ns.adobe.com/xap/1.0/rights/"),
+    ("xmpMM", "http://ns.adobe.com/xap/1.0/mm/"),
+    ("photoshop", "http://ns.adobe.com/photoshop/1.0/"),
+    ("tiff", "http://ns.adobe.com/tiff/1.0/"),
+    ("exif", "http://ns.adobe.com/exif/1.0/"),
+    ("MicrosoftPhoto", "http://ns.microsoft.com/photo/1.0/"),
+];
+
+/// The namespaces of the RDF syntax around the properties
+const SYNTAX_NAMESPACES: [(&str, &str); 3] = [
+    ("rdf", "http://www.w3.org/1999/02/22-rdf-syntax-ns#"),
+    ("xml", "http://www.w3.org/XML/1998/namespace"),
+    ("xmlns", "http://www.w3.org/2000/xmlns/"),
+];
+
+fn known_namespace(prefix: &str) -> Option<(&'static str, &'static str)> {
+    NAMESPACES
+        .into_iter()
+        .chain(SYNTAX_NAMESPACES)
+        .find(|(known, _)| *known == prefix)
+}
+
+/// Returns `name` with the prefix its namespace is known by, or as written when the namespace
+/// isn't a known one.
+fn canonical(resolved: (ResolveResult, LocalName), name: QName) -> String {
+    if let (ResolveResult::Bound(Namespace(uri)), local) = resolved
+        && let Some((prefix, _)) = NAMESPACES
+            .into_iter()
+            .chain(SYNTAX_NAMESPACES)
+            .find(|(_, known)| known.as_bytes() == uri)
+    {
+        return format!("{}:{}", prefix, String::from_utf8_lossy(local.as_ref()));
+    }
+    String::from_utf8_lossy(name.as_ref()).into_owned()
+}
+
+fn qname(start: &BytesStart) -> String {
+    String::from_utf8_lossy(start.name().as_ref()).into_owned()
+}
+
+fn element_name(reader: &NsReader<&[u8]>, name: QName) -> String {
+    canonical(reader.resolve_element(name), name)
+}
+
+fn attribute_name(reader: &NsReader<&[u8]>, name: QName) -> String {
+    canonical(reader.resolve_attribute(name), name)
+}
+
+fn attributes(reader: &NsReader<&[u8]>, start: &BytesStart) -> Vec<(String, String)> {
+    start
+        .attributes()
+        .filter_map(Result::ok)
+        .filter_map(|attr| {
+            let key = attribute_name(reader, attr.key);
+            let value = attr.unescape_value().ok()?.into_owned();
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn is_syntax_attribute(name: &str) -> bool {
+    name.starts_with("xmlns") || name.starts_with("rdf:") || name.starts_with("xml:")
+}
+
+pub fn parse(data: &[u8]) -> Option<Xmp> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut reader = NsReader::from_str(text);
+
+    let mut xmp = Xmp::default();
+    let mut depth = 0usize;
+    let mut description_depth: Option<usize> = None;
+    let mut property: Option<Property> = None;
+    let mut item: Option<(String, String)> = None;
+
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(err) => {
+                log::trace!("xmp::parse: {:?}", err);
+                // Keep what has been read so far
+                break;
+            }
+        };
+
+        let (start, is_empty) = match &event {
+            Event::Start(start) => (Some(start), false),
+            Event::Empty(start) => (Some(start), true),
+            _ => (None, false),
+        };
+
+        if let Some(start) = start {
+            depth += 1;
+            let name = element_name(&reader, start.name());
+
+            if name == "rdf:Description" && property.is_none() {
+                description_depth = Some(depth);
+                // Simple properties can be written as attributes
+                for (key, value) in attributes(&reader, start) {
+                    if !is_syntax_attribute(&key) {
+                        xmp.properties.push((key, XmpValue::Text(value)));
+                    }
+                }
+            } else if property.is_none() && description_depth == Some(depth - 1) {
+                let resource = attributes(&reader, start)
+                    .into_iter()
+                    .find(|(key, _)| key == "rdf:resource")
+                    .map(|(_, value)| value);
+                property = Some(Property {
+                    name,
+                    value: XmpValue::Text(resource.unwrap_or_default()),
+                    depth,
+                });
+            } else if let Some(property) = property.as_mut() {
+                match name.as_str() {
+                    "rdf:Bag" | "rdf:Seq" if depth == property.depth + 1 => {
+                        property.value = XmpValue::Array(Vec::new());
+                    }
+                    "rdf:Alt" if depth == property.depth + 1 => {
+                        property.value = XmpValue::LangAlt(Vec::new());
+                    }
+                    "rdf:li" if depth == property.depth + 2 => {
+                        let lang = attributes(&reader, start)
+                            .into_iter()
+                            .find(|(key, _)| key == "xml:lang")
+                            .map(|(_, value)| value)
+                            .unwrap_or_default();
+                        item = Some((lang, String::new()));
+                    }
+                    _ => {}
+                }
+            }
+
+            if !is_empty {
+                continue;
+            }
+        }
+
+        match event {
+            Event::Text(text) => {
+                let Ok(text) = text.unescape() else {
+                    continue;
+                };
+                if let Some((_, item_text)) = item.as_mut() {
+                    item_text.push_str(&text);
+                } else if let Some(property) = property.as_mut()
+                    && property.depth == depth
+                    && let XmpValue::Text(value) = &mut property.value
+                {
+                    value.push_str(&text);
+                }
+            }
+            Event::End(_) | Event::Empty(_) => {
+                if let Some(property) = property.as_mut()
+                    && depth == property.depth + 2
+                    && let Some((lang, text)) = item.take()
+                {
+                    let text = text.trim().to_string();
+                    match &mut property.value {
+                        XmpValue::Array(items) => items.push(text),
+                        XmpValue::LangAlt(items) => items.push((lang, text)),
+                        XmpValue::Text(_) => {}
+                    }
+                }
+
+                if let Some(Property { name, value, .. }) =
+                    property.take_if(|property| property.depth == depth)
+                {
+                    let value = match value {
+                        XmpValue::Text(text) => XmpValue::Text(text.trim().to_string()),
+                        value => value,
+                    };
+                    let is_empty = match &value {
+                        XmpValue::Text(text) => text.is_empty(),
+                        _ => false,
+                    };
+                    if !is_empty {
+                        xmp.properties.push((name, value));
+                    }
+                }
+
+                if description_depth == Some(depth) {
+                    description_depth = None;
+                }
+                depth = depth.saturating_sub(1);
+            }
+            _ => {}
+        }
+    }
+
+    Some(xmp)
+}
+
+const EMPTY_PACKET: &str = concat!(
+    "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+    "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+    "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+    "<rdf:Description rdf:about=\"\"/>",
+    "</rdf:RDF>",
+    "</x:xmpmeta>",
+    "<?xpacket end=\"w\"?>",
+);
+
+/// The prefixes that new elements are written with: one that is already bound to the namespace
+/// where they go, or else a free one that gets declared on rdf:Description.
+struct Prefixes {
+    /// Pairs of the prefix a namespace is known by and the prefix to write
+    chosen: Vec<(&'static str, String)>,
+    /// Pairs of a prefix and a namespace to declare
+    declarations: Vec<(String, &'static str)>,
+}
+
+impl Prefixes {
+    fn new(reader: &NsReader<&[u8]>, edits: &[(&str, Option<XmpValue>)]) -> Self {
+        let bound: Vec<(&[u8], &[u8])> = reader
+            .prefixes()
+            .filter_map(|(declaration, Namespace(uri))| match declaration {
+                PrefixDeclaration::Named(prefix) => Some((prefix, uri)),
+                PrefixDeclaration::Default => None,
+            })
+            .collect();
+        let used = edits
+            .iter()
+            .filter(|(_, value)| value.is_some())
+            .filter_map(|(name, _)| name.split_once(':'))
+            .map(|(prefix, _)| prefix);
+
+        let mut prefixes = Prefixes {
+            chosen: Vec::new(),
+            declarations: Vec::new(),
+        };
+        for prefix in std::iter::once("rdf").chain(used) {
+            let Some((known, uri)) = known_namespace(prefix) else {
+                continue;
+            };
+            if prefixes.chosen.iter().any(|(chosen, _)| *chosen == known) {
+                continue;
+            }
+            let prefix = match bound.iter().find(|(_, bound)| *bound == uri.as_bytes()) {
+                Some((prefix, _)) => String::from_utf8_lossy(prefix).into_owned(),
+                None => {
+                    // The usual prefix, unless it is bound to another namespace
+                    let prefix = (0..)
+                        .map(|n| match n {
+                            0 => known.to_string(),
+                            n => format!("{}{}", known, n),
+                        })
+                        .find(|prefix| {
+                            !bound.iter().any(|(bound, _)| *bound == prefix.as_bytes())
+                                && !prefixes.declarations.iter().any(|(d, _)| d == prefix)
+                        })
+                        .unwrap();
+                    prefixes.declarations.push((prefix.clone(), uri));
+                    prefix
+                }
+            };
+            prefixes.chosen.push((known, prefix));
+        }
+        prefixes
+    }
+
+    /// Returns `name`, given with the prefix its namespace is known by, as it is to be written.
+    fn name(&self, name: &str) -> String {
+        name.split_once(':')
+            .and_then(|(known, local)| {
+                let (_, prefix) = self.chosen.iter().find(|(chosen, _)| *chosen == known)?;
+                Some(format!("{}:{}", prefix, local))
+            })
+            .unwrap_or_else(|| name.to_string())
+    }
+}
+
+/// Returns `data`, or a new packet when there is none, with the properties in `edits`
+/// replaced. `None` removes a property. The edits are named with the prefixes of
+/// `NAMESPACES`, and replace a property in the same namespace whatever its prefix. The new
+/// values go into the first rdf:Description. Fails when `data` isn't well-formed or has no
+/// rdf:RDF, rather than dropping what it holds.
+pub fn update(data: Option<&[u8]>, edits: &[(&str, Option<XmpValue>)]) -> Option<Vec<u8>> {
+    let text = match data {
+        Some(data) => std::str::from_utf8(data).ok()?,
+        None => EMPTY_PACKET,
+    };
+    let mut reader = NsReader::from_str(text);
+    let mut writer = Writer::new(Vec::new());
+    let is_edited = |name: &str| {
+        edits
+            .iter()
+            .any(|(edit, _)| edit.eq_ignore_ascii_case(name))
+    };
+
+    let mut depth = 0usize;
+    let mut description_depth: Option<usize> = None;
+    // Element depth of a replaced property while its old value is being skipped
+    let mut skip_depth: Option<usize> = None;
+    let mut inserted = false;
+
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(err) => {
+                log::trace!("xmp::update: {:?}", err);
+                return None;
+            }
+        };
+
+        if let Some(skipped) = skip_depth {
+            match event {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => {
+                    if skipped == depth {
+                        skip_depth = None;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::Start(ref start) | Event::Empty(ref start) => {
+                let is_empty = matches!(event, Event::Empty(_));
+                let name = element_name(&reader, start.name());
+
+                if description_depth == Some(depth) && is_edited(&name) {
+                    if !is_empty {
+                        skip_depth = Some(depth + 1);
+                        depth += 1;
+                    }
+                    continue;
+                }
+
+                if name != "rdf:Description" || description_depth.is_some() {
+                    if !is_empty {
+                        depth += 1;
+                    }
+                    writer.write_event(event.borrow()).ok()?;
+                    continue;
+                }
+
+                // Simple properties can be attributes of rdf:Description
+                let mut description = BytesStart::new(qname(start));
+                description.extend_attributes(
+                    start
+                        .attributes()
+                        .filter_map(Result::ok)
+                        .filter(|attr| !is_edited(&attribute_name(&reader, attr.key))),
+                );
+                if !inserted {
+                    let prefixes = Prefixes::new(&reader, edits);
+                    write_description(&mut writer, description, &prefixes, edits).ok()?;
+                    inserted = true;
+                    if is_empty {
+                        writer
+                            .write_event(Event::End(BytesEnd::new(qname(start))))
+                            .ok()?;
+                    }
+                } else if is_empty {
+                    writer.write_event(Event::Empty(description)).ok()?;
+                } else {
+                    writer.write_event(Event::Start(description)).ok()?;
+                }
+                if !is_empty {
+                    depth += 1;
+                    description_depth = Some(depth);
+                }
+            }
+            Event::End(ref end) => {
+                if !inserted && element_name(&reader, end.name()) == "rdf:RDF" {
+                    let prefixes = Prefixes::new(&reader, edits);
+                    let name = prefixes.name("rdf:Description");
+                    let mut description = BytesStart::new(name.as_str());
+                    description.push_attribute((prefixes.name("rdf:about").as_str(), ""));
+                    write_description(&mut writer, description, &prefixes, edits).ok()?;
+                    writer.write_event(Event::End(BytesEnd::new(name))).ok()?;
+                    inserted = true;
+                }
+                if description_depth == Some(depth) {
+                    description_depth = None;
+                }
+                depth = depth.saturating_sub(1);
+                writer.write_event(event.borrow()).ok()?;
+            }
+            event => writer.write_event(event).ok()?,
+        }
+    }
+
+    inserted.then(|| writer.into_inner())
+}
+
+/// Writes the start tag of rdf:Description, declaring the namespaces it lacks, and the new
+/// values.
+fn write_description(
+    writer: &mut Writer<Vec<u8>>,
+    mut description: BytesStart,
+    prefixes: &Prefixes,
+    edits: &[(&str, Option<XmpValue>)],
+) -> std::io::Result<()> {
+    for (prefix, uri) in &prefixes.declarations {
+        description.push_attribute((format!("xmlns:{}", prefix).as_str(), *uri));
+    }
+    writer.write_event(Event::Start(description))?;
+
+    for (name, value) in edits {
+        let Some(value) = value else {
+            continue;
+        };
+        let element = prefixes.name(name);
+        writer.write_event(Event::Start(BytesStart::new(element.as_str())))?;
+        match value {
+            XmpValue::Text(text) => writer.write_event(Event::Text(BytesText::new(text)))?,
+            XmpValue::Array(items) => {
+                // dc:creator is the one ordered array
+                let kind = prefixes.name(if *name == "dc:creator" {
+                    "rdf:Seq"
+                } else {
+                    "rdf:Bag"
+                });
+                writer.write_event(Event::Start(BytesStart::new(kind.as_str())))?;
+                for item in items {
+                    write_item(writer, prefixes, None, item)?;
+                }
+                writer.write_event(Event::End(BytesEnd::new(kind)))?;
+            }
+            XmpValue::LangAlt(items) => {
+                let kind = prefixes.name("rdf:Alt");
+                writer.write_event(Event::Start(BytesStart::new(kind.as_str())))?;
+                for (lang, text) in items {
+                    write_item(writer, prefixes, Some(lang), text)?;
+                }
+                writer.write_event(Event::End(BytesEnd::new(kind)))?;
+            }
+        }
+        writer.write_event(Event::End(BytesEnd::new(element)))?;
+    }
+    Ok(())
+}
+
+fn write_item(
+    writer: &mut Writer<Vec<u8>>,
+    prefixes: &Prefixes,
+    lang: Option<&str>,
+    text: &str,
+) -> std::io::Result<()> {
+    let name = prefixes.name("rdf:li");
+    let mut li = BytesStart::new(name.as_str());
+    if let Some(lang) = lang {
+        li.push_attribute(("xml:lang", lang));
+    }
+    writer.write_event(Event::Start(li))?;
+    writer.write_event(Event::Text(BytesText::new(text)))?;
+    writer.write_event(Event::End(BytesEnd::new(name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(description: &str) -> String {
+        format!(
+            concat!(
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+                "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+                "{}",
+                "</rdf:RDF>",
+                "</x:xmpmeta>",
+            ),
+            description
+        )
+    }
+
+    #[test]
+    fn parse_containers() {
+        let xmp = parse(
+            packet(concat!(
+                "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">",
+                "<dc:title><rdf:Alt>",
+                "<rdf:li xml:lang=\"de\">Titel</rdf:li>",
+                "<rdf:li xml:lang=\"x-default\">Title</rdf:li>",
+                "</rdf:Alt></dc:title>",
+                "<dc:subject><rdf:Bag><rdf:li>a</rdf:li><rdf:li>b</rdf:li></rdf:Bag></dc:subject>",
+                "<dc:creator><rdf:Seq><rdf:li>Someone</rdf:li></rdf:Seq></dc:creator>",
+                "</rdf:Description>",
+            ))
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let Some(XmpValue::LangAlt(title)) = xmp.get("dc:title") else {
+            panic!("dc:title");
+        };
+        assert_eq!(title.len(), 2);
+        assert_eq!(xmp.get("dc:title").unwrap().as_text(), Some("Title"));
+        let Some(XmpValue::Array(subject)) = xmp.get("dc:subject") else {
+            panic!("dc:subject");
+        };
+        assert_eq!(subject, &["a", "b"]);
+        assert_eq!(xmp.get("dc:creator").unwrap().as_text(), Some("Someone"));
+    }
+
+    #[test]
+    fn parse_other_prefixes() {
+        let xmp = parse(
+            packet(concat!(
+                "<rdf:Description rdf:about=\"\" xmlns:xap=\"http://ns.adobe.com/xap/1.0/\" ",
+                "xmlns:e=\"http://purl.org/dc/elements/1.1/\" xap:Rating=\"4\">",
+                "<e:subject><rdf:Bag><rdf:li>a</rdf:li></rdf:Bag></e:subject>",
+                "</rdf:Description>",
+            ))
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(xmp.get("xmp:Rating").unwrap().as_text(), Some("4"));
+        assert_eq!(xmp.get("dc:subject").unwrap().as_text(), Some("a"));
+        assert!(xmp.get("xap:Rating").is_none());
+
+        let xmp = parse(
+            concat!(
+                "<R:RDF xmlns:R=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+                "<R:Description xmlns:dc=\"http://purl.org/dc/elements/1.1/\">",
+                "<dc:title><R:Alt><R:li xml:lang=\"x-default\">Title</R:li></R:Alt></dc:title>",
+                "</R:Description>",
+                "</R:RDF>",
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(xmp.get("dc:title").unwrap().as_text(), Some("Title"));
+    }
+
+    #[test]
+    fn parse_attributes() {
+        let xmp = parse(
+            packet(concat!(
+                "<rdf:Description rdf:about=\"\" xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" ",
+                "xmlns:u=\"urn:unknown\" xmp:Rating=\"3\" u:Other=\"x\"/>",
+            ))
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let names: Vec<&str> = xmp
+            .properties
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["xmp:Rating", "u:Other"]);
+        assert_eq!(xmp.get("xmp:Rating").unwrap().as_text(), Some("3"));
+    }
+
+    #[test]
+    fn update_other_prefix() {
+        let data = packet(concat!(
+            "<rdf:Description rdf:about=\"\" xmlns:xap=\"http://ns.adobe.com/xap/1.0/\" ",
+            "xap:Rating=\"1\">",
+            "<xap:Label>Red</xap:Label>",
+            "</rdf:Description>",
+        ));
+        let edits = [
+            ("xmp:Rating", Some(XmpValue::Text("5".to_string()))),
+            ("xmp:Label", None),
+        ];
+        let updated = update(Some(data.as_bytes()), &edits).unwrap();
+
+        let text = String::from_utf8(updated.clone()).unwrap();
+        assert_eq!(
+            text.matches("<xap:Rating>5</xap:Rating>").count(),
+            1,
+            "{}",
+            text
+        );
+        assert!(
+            !text.contains("Rating=") && !text.contains("Label"),
+            "{}",
+            text
+        );
+        assert!(!text.contains("xmlns:xmp="), "{}", text);
+        let xmp = parse(&updated).unwrap();
+        assert_eq!(xmp.get("xmp:Rating").unwrap().as_text(), Some("5"));
+        assert!(xmp.get("xmp:Label").is_none());
+    }
+
+    #[test]
+    fn update_round_trip() {
+        let edits = [
+            ("xmp:Rating", Some(XmpValue::Text("4".to_string()))),
+            (
+                "dc:title",
+                Some(XmpValue::LangAlt(vec![(
+                    "x-default".to_string(),
+                    "A & B".to_string(),
+                )])),
+            ),
+            (
+                "dc:subject",
+                Some(XmpValue::Array(vec![
+                    "cat".to_string(),
+                    "<dog>".to_string(),
+                ])),
+            ),
+            (
+                "dc:creator",
+                Some(XmpValue::Array(vec!["Someone".to_string()])),
+            ),
+            (
+                "dc:description",
+                Some(XmpValue::LangAlt(vec![(
+                    "x-default".to_string(),
+                    "Text".to_string(),
+                )])),
+            ),
+        ];
+        let xmp = parse(&update(None, &edits).unwrap()).unwrap();
+        assert_eq!(xmp.properties.len(), edits.len());
+        for (name, value) in &edits {
+            let parsed = xmp.get(name).unwrap();
+            assert_eq!(
+                format!("{:?}", parsed),
+                format!("{:?}", value.as_ref().unwrap())
+            );
+        }
+
+        // Editing again replaces the values rather than adding to them, and removes.
+        let data = update(None, &edits).unwrap();
+        let edits = [
+            ("xmp:Rating", Some(XmpValue::Text("1".to_string()))),
+            ("dc:subject", None),
+        ];
+        let xmp = parse(&update(Some(&data), &edits).unwrap()).unwrap();
+        assert_eq!(xmp.properties.len(), 4);
+        assert_eq!(xmp.get("xmp:Rating").unwrap().as_text(), Some("1"));
+        assert!(xmp.get("dc:subject").is_none());
+        assert_eq!(xmp.get("dc:title").unwrap().as_text(), Some("A & B"));
+    }
+
+    #[test]
+    fn update_rejects_malformed() {
+        let edits = [("xmp:Rating", Some(XmpValue::Text("1".to_string())))];
+        assert!(
+            update(Some(b"<x:xmpmeta><rdf:RDF>"), &edits).is_none(),
+            "unclosed"
+        );
+        assert!(update(Some(b"<a></b>"), &edits).is_none(), "mismatched");
+        assert!(
+            update(Some(b"<x:xmpmeta/>"), &edits).is_none(),
+            "no rdf:RDF"
+        );
+        assert!(update(Some(&[0xff, 0xfe]), &edits).is_none(), "not UTF-8");
+    }
+}
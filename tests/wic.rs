@@ -1,7 +1,152 @@
-use jxl_winthumb::JXLWICBitmapDecoder;
+use half::f16;
+use jxl_winthumb::{IJXLDecodeControl, JXLPropertyStore, JXLWICBitmapDecoder, PKEY_HAS_ALPHA};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use windows as Windows;
+use windows::Win32::Foundation::{
+    BOOL, E_NOTIMPL, ERROR_TIMEOUT, S_FALSE, S_OK, STG_E_ACCESSDENIED,
+};
 use windows::Win32::Graphics::Imaging::*;
-use windows::Win32::System::Com::{CLSCTX_INPROC_SERVER, CoCreateInstance, CoInitialize};
-use windows::Win32::UI::Shell::SHCreateMemStream;
+use windows::Win32::System::Com::{
+    CLSCTX_INPROC_SERVER, CoCreateInstance, CoInitialize, CoTaskMemFree, ISequentialStream_Impl,
+    IStream, IStream_Impl, LOCKTYPE, STATFLAG, STATSTG, STGC, STGM_READ, STGM_READWRITE,
+    STREAM_SEEK, STREAM_SEEK_CUR, STREAM_SEEK_END, STREAM_SEEK_SET,
+    StructuredStorage::{
+        InitPropVariantFromStringVector, PropVariantGetElementCount, PropVariantGetStringElem,
+        PropVariantToBuffer,
+    },
+};
+use windows::Win32::System::Variant::VT_LPSTR;
+use windows::Win32::UI::Shell::PropertiesSystem::{
+    IInitializeWithStream, IPropertyStore, IPropertyStoreCapabilities, PROPERTYKEY,
+};
+use windows::Win32::UI::Shell::{
+    IDestinationStreamFactory_Impl, SHCreateMemStream, SHCreateStreamOnFileEx,
+};
+use windows::core::{GUID, HRESULT, HSTRING, Interface, PCWSTR, PROPVARIANT, PWSTR, implement};
+
+fn stream(data: &[u8]) -> IStream {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+    unsafe { SHCreateMemStream(Some(data)) }.expect("Create an IStream")
+}
+
+fn decoder_for(data: &[u8]) -> IWICBitmapDecoder {
+    let stream = stream(data);
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
+    decoder
+}
+
+/// Wraps a bare codestream in a container, followed by `boxes`.
+fn container(codestream: &[u8], boxes: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let mut data = b"\0\0\0\x0cJXL \r\n\x87\n\0\0\0\x14ftypjxl \0\0\0\0jxl ".to_vec();
+    for (box_type, payload) in std::iter::once((b"jxlc", codestream)).chain(boxes.iter().copied()) {
+        data.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+    }
+    data
+}
+
+/// An IFD entry: the tag, the TIFF type, and the value in little endian
+type Entry = (u16, u16, Vec<u8>);
+
+fn ascii(tag: u16, text: &str) -> Entry {
+    (tag, 2, [text.as_bytes(), &[0]].concat())
+}
+
+fn short(tag: u16, value: u16) -> Entry {
+    (tag, 3, value.to_le_bytes().to_vec())
+}
+
+fn rationals(tag: u16, values: &[(u32, u32)]) -> Entry {
+    let bytes = values
+        .iter()
+        .flat_map(|(num, denom)| [num.to_le_bytes(), denom.to_le_bytes()].concat())
+        .collect();
+    (tag, 5, bytes)
+}
+
+/// Returns the payload of an `Exif` box: the offset of the TIFF header, then a little endian
+/// TIFF structure with the Exif and GPS IFDs linked from IFD0.
+fn exif_payload(ifd0: &[Entry], exif: &[Entry], gps: &[Entry]) -> Vec<u8> {
+    let ifd_len = |count: usize| 2 + 12 * count + 4;
+    let exif_offset = 8 + ifd_len(ifd0.len() + 2);
+    let gps_offset = exif_offset + ifd_len(exif.len());
+    let values_offset = gps_offset + ifd_len(gps.len());
+
+    let mut ifd0 = ifd0.to_vec();
+    ifd0.push((0x8769, 4, (exif_offset as u32).to_le_bytes().to_vec()));
+    ifd0.push((0x8825, 4, (gps_offset as u32).to_le_bytes().to_vec()));
+
+    let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+    let mut values = Vec::new();
+    for ifd in [&ifd0[..], exif, gps] {
+        tiff.extend_from_slice(&(ifd.len() as u16).to_le_bytes());
+        for (tag, kind, value) in ifd {
+            let size = match kind {
+                3 => 2,
+                4 => 4,
+                5 => 8,
+                _ => 1,
+            };
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&((value.len() / size) as u32).to_le_bytes());
+            if value.len() <= 4 {
+                let mut inline = value.clone();
+                inline.resize(4, 0);
+                tiff.extend_from_slice(&inline);
+            } else {
+                let offset = values_offset + values.len();
+                tiff.extend_from_slice(&(offset as u32).to_le_bytes());
+                values.extend_from_slice(value);
+            }
+        }
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+    }
+    tiff.extend_from_slice(&values);
+    [&0u32.to_be_bytes()[..], &tiff].concat()
+}
+
+fn property_store(data: &[u8]) -> IPropertyStore {
+    let stream = stream(data);
+    let store: IInitializeWithStream = JXLPropertyStore::default().into();
+    unsafe { store.Initialize(&stream, STGM_READ.0) }.expect("Initialize the property store");
+    store.cast().expect("Cast to IPropertyStore")
+}
+
+fn get(reader: &IWICMetadataQueryReader, name: &str) -> windows::core::Result<PROPVARIANT> {
+    let name = HSTRING::from(name);
+    let mut value = PROPVARIANT::new();
+    unsafe { reader.GetMetadataByName(PCWSTR(name.as_ptr()), &mut value) }?;
+    Ok(value)
+}
+
+fn enumerate(reader: &IWICMetadataQueryReader) -> Vec<String> {
+    let enumerator = unsafe { reader.GetEnumerator() }.expect("GetEnumerator");
+    let mut items = vec![];
+    loop {
+        let mut item = [PWSTR::null()];
+        let mut fetched = 0u32;
+        let _ = unsafe { enumerator.Next(&mut item, Some(&mut fetched)) };
+        if fetched == 0 {
+            return items;
+        }
+        items.push(unsafe { item[0].to_string() }.expect("A string"));
+        unsafe { CoTaskMemFree(Some(item[0].0 as _)) };
+    }
+}
+
+fn bytes_per_pixel(format: &GUID) -> usize {
+    match *format {
+        GUID_WICPixelFormat32bppBGRA | GUID_WICPixelFormat32bppPBGRA => 4,
+        GUID_WICPixelFormat16bppGray => 2,
+        GUID_WICPixelFormat48bppRGB => 6,
+        _ => 8,
+    }
+}
 
 #[test]
 fn basic() {
@@ -46,3 +191,1345 @@ fn basic() {
     assert_eq!(pixels[2], 0, "blue");
     assert_eq!(pixels[3], 255, "alpha");
 }
+
+#[test]
+fn thumbnail() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let stream = unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream");
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
+    let thumbnail = unsafe { decoder.GetThumbnail() }.expect("Get the thumbnail");
+
+    let mut width = 0u32;
+    let mut height = 0u32;
+    unsafe { thumbnail.GetSize(&mut width, &mut height).expect("GetSize") };
+    assert_eq!(width, 256, "width");
+    assert_eq!(height, 256, "height");
+}
+
+#[test]
+fn source_transform() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let stream = unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream");
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
+    let frame = unsafe { decoder.GetFrame(0) }.expect("Get the first frame");
+    let transform: IWICBitmapSourceTransform = frame.cast().expect("Cast to the transform");
+
+    let mut width = 512u32;
+    let mut height = 512u32;
+    unsafe { transform.GetClosestSize(&mut width, &mut height) }.expect("GetClosestSize");
+    assert_eq!(width, 512, "width");
+    assert_eq!(height, 512, "height");
+
+    let mut format = GUID::zeroed();
+    unsafe { transform.GetClosestPixelFormat(&mut format) }.expect("GetClosestPixelFormat");
+    assert_eq!(
+        format,
+        unsafe { frame.GetPixelFormat() }.expect("GetPixelFormat"),
+        "pixel format"
+    );
+
+    let supported = unsafe { transform.DoesSupportTransform(WICBitmapTransformRotate90) }
+        .expect("DoesSupportTransform");
+    assert!(supported.as_bool(), "rotation support");
+
+    let stride = 256 * 8;
+    let mut pixels: Vec<u8> = vec![0; stride * 128];
+    unsafe {
+        transform.CopyPixels(
+            &WICRect {
+                X: 0,
+                Y: 0,
+                Width: 128,
+                Height: 256,
+            },
+            512,
+            512,
+            &format,
+            WICBitmapTransformRotate90,
+            stride as u32,
+            &mut pixels,
+        )
+    }
+    .expect("Copy pixels");
+
+    let bpp = bytes_per_pixel(&format);
+    let mut reference: Vec<u8> = vec![0; 1024 * 1024 * bpp];
+    unsafe { frame.CopyPixels(std::ptr::null(), (1024 * bpp) as u32, &mut reference) }
+        .expect("Copy the frame");
+
+    // A quarter turn and then a horizontal flip transposes the rectangle.
+    let crop = WICRect {
+        X: 100,
+        Y: 200,
+        Width: 300,
+        Height: 150,
+    };
+    let stride = 150 * bpp;
+    let mut transposed: Vec<u8> = vec![0; stride * 300];
+    unsafe {
+        transform.CopyPixels(
+            &crop,
+            1024,
+            1024,
+            &format,
+            WICBitmapTransformOptions(
+                WICBitmapTransformRotate90.0 | WICBitmapTransformFlipHorizontal.0,
+            ),
+            stride as u32,
+            &mut transposed,
+        )
+    }
+    .expect("Copy transposed pixels");
+    for y in 0..300 {
+        for x in 0..150 {
+            let src = ((200 + x) * 1024 + 100 + y) * bpp;
+            let dst = y * stride + x * bpp;
+            assert_eq!(
+                transposed[dst..dst + bpp],
+                reference[src..src + bpp],
+                "pixel {x},{y}"
+            );
+        }
+    }
+
+    // A rectangle of the scaled frame is the same as that part of the whole scaled frame.
+    let mut scaled: Vec<u8> = vec![0; 512 * 512 * bpp];
+    unsafe {
+        transform.CopyPixels(
+            std::ptr::null(),
+            512,
+            512,
+            &format,
+            WICBitmapTransformRotate0,
+            (512 * bpp) as u32,
+            &mut scaled,
+        )
+    }
+    .expect("Copy scaled pixels");
+    let part = WICRect {
+        X: 37,
+        Y: 101,
+        Width: 200,
+        Height: 64,
+    };
+    let stride = 200 * bpp;
+    let mut cropped: Vec<u8> = vec![0; stride * 64];
+    unsafe {
+        transform.CopyPixels(
+            &part,
+            512,
+            512,
+            &format,
+            WICBitmapTransformRotate0,
+            stride as u32,
+            &mut cropped,
+        )
+    }
+    .expect("Copy scaled and cropped pixels");
+    for y in 0..64 {
+        let src = ((101 + y) * 512 + 37) * bpp;
+        assert_eq!(
+            cropped[y * stride..(y + 1) * stride],
+            scaled[src..src + stride],
+            "row {y}"
+        );
+    }
+
+    let empty = WICRect {
+        X: 10,
+        Y: 10,
+        Width: 0,
+        Height: 20,
+    };
+    unsafe {
+        transform.CopyPixels(
+            &empty,
+            1024,
+            1024,
+            &format,
+            WICBitmapTransformRotate90,
+            4,
+            &mut pixels,
+        )
+    }
+    .expect("Copy an empty rectangle");
+}
+
+#[test]
+fn copy_pixels_stride() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let stream = unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream");
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
+    let frame = unsafe { decoder.GetFrame(0) }.expect("Get the first frame");
+
+    let format = unsafe { frame.GetPixelFormat() }.expect("GetPixelFormat");
+    let bytes_per_pixel = if format == GUID_WICPixelFormat32bppBGRA {
+        4
+    } else if format == GUID_WICPixelFormat48bppRGB {
+        6
+    } else {
+        8
+    };
+
+    // 16 bytes of padding per row
+    let stride = 1024 * bytes_per_pixel + 16;
+    let mut pixels: Vec<u8> = vec![0xcc; stride * 1024];
+    unsafe { frame.CopyPixels(std::ptr::null(), stride as u32, &mut pixels) }.expect("Copy pixels");
+    assert_eq!(
+        &pixels[1024 * bytes_per_pixel..stride],
+        &[0xcc; 16],
+        "padding"
+    );
+
+    let err = unsafe { frame.CopyPixels(std::ptr::null(), stride as u32, &mut pixels[1..]) }
+        .expect_err("Buffer too small");
+    assert_eq!(
+        err.code(),
+        windows::Win32::Foundation::WINCODEC_ERR_INSUFFICIENTBUFFER
+    );
+
+    let rect = WICRect {
+        X: 1000,
+        Y: 0,
+        Width: 100,
+        Height: 1,
+    };
+    let err = unsafe { frame.CopyPixels(&rect, stride as u32, &mut pixels) }
+        .expect_err("Rect out of bounds");
+    assert_eq!(err.code(), windows::Win32::Foundation::E_INVALIDARG);
+}
+
+#[test]
+fn copy_pixels_tiled() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let full = unsafe { decoder_for(&mem).GetFrame(0) }.expect("Get the first frame");
+    let format = unsafe { full.GetPixelFormat() }.expect("GetPixelFormat");
+    let bytes_per_pixel = bytes_per_pixel(&format);
+    let stride = 1024 * bytes_per_pixel;
+    let mut expected: Vec<u8> = vec![0; stride * 1024];
+    unsafe { full.CopyPixels(std::ptr::null(), stride as u32, &mut expected) }
+        .expect("Copy pixels");
+
+    // 16 tiles of 256 x 256, so that small rectangles render only the tiles they touch
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::with_tile_size(256).into();
+    unsafe { decoder.Initialize(&stream(&mem), WICDecodeOptions(0)) }
+        .expect("Initialize the decoder");
+    let frame = unsafe { decoder.GetFrame(0) }.expect("Get the first frame");
+    assert_eq!(
+        unsafe { frame.GetPixelFormat() }.expect("GetPixelFormat"),
+        format
+    );
+
+    // Within one tile, across four tiles, and again over tiles rendered for the last one
+    for rect in [
+        WICRect {
+            X: 10,
+            Y: 20,
+            Width: 30,
+            Height: 40,
+        },
+        WICRect {
+            X: 200,
+            Y: 240,
+            Width: 300,
+            Height: 100,
+        },
+        WICRect {
+            X: 250,
+            Y: 250,
+            Width: 20,
+            Height: 20,
+        },
+    ] {
+        let row_bytes = rect.Width as usize * bytes_per_pixel;
+        let mut pixels: Vec<u8> = vec![0; row_bytes * rect.Height as usize];
+        unsafe { frame.CopyPixels(&rect, row_bytes as u32, &mut pixels) }.expect("Copy pixels");
+        for (y, row) in pixels.chunks_exact(row_bytes).enumerate() {
+            let start = (rect.Y as usize + y) * stride + rect.X as usize * bytes_per_pixel;
+            assert!(
+                row == &expected[start..start + row_bytes],
+                "{:?} row {}",
+                rect,
+                y
+            );
+        }
+    }
+}
+
+#[test]
+fn metadata_query_reader() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let stream = unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream");
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
+    let reader = unsafe { decoder.GetMetadataQueryReader() }.expect("Get the query reader");
+
+    let format = unsafe { reader.GetContainerFormat() }.expect("GetContainerFormat");
+    assert_eq!(
+        format,
+        JXLWICBitmapDecoder::CONTAINER_ID,
+        "container format"
+    );
+
+    let err = unsafe {
+        reader.GetMetadataByName(windows::core::w!("/nonexistent"), std::ptr::null_mut())
+    }
+    .expect_err("Missing item");
+    assert_eq!(
+        err.code(),
+        windows::Win32::Foundation::WINCODEC_ERR_PROPERTYNOTFOUND
+    );
+    assert!(get(&reader, "/Incomplete").is_err(), "a complete image");
+}
+
+#[test]
+fn metadata_query_reader_items() {
+    let exif = exif_payload(
+        &[ascii(0x010f, "Maker")],
+        &[short(0x8827, 200)],
+        &[ascii(0x0001, "N")],
+    );
+    let xmp = concat!(
+        "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+        "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+        "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" ",
+        "xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" xmp:Rating=\"3\">",
+        "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">Title</rdf:li></rdf:Alt></dc:title>",
+        "</rdf:Description>",
+        "</rdf:RDF>",
+        "</x:xmpmeta>",
+    );
+    let codestream = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let decoder = decoder_for(&container(
+        &codestream,
+        &[(b"Exif", &exif), (b"xml ", xmp.as_bytes())],
+    ));
+    let reader = unsafe { decoder.GetMetadataQueryReader() }.expect("Get the query reader");
+    assert_eq!(enumerate(&reader), ["/app1", "/xmp"]);
+
+    let make = get(&reader, "/app1/ifd/{ushort=271}").expect("Make");
+    assert_eq!(
+        unsafe { make.as_raw().Anonymous.Anonymous.vt },
+        VT_LPSTR.0,
+        "ASCII as VT_LPSTR"
+    );
+    assert_eq!(make.to_string(), "Maker");
+    let iso = get(&reader, "/app1/ifd/exif/{ushort=34855}").expect("ISOSpeed");
+    assert_eq!(u16::try_from(&iso).ok(), Some(200));
+    let latitude_ref = get(&reader, "/app1/ifd/gps/{ushort=1}").expect("GPSLatitudeRef");
+    assert_eq!(latitude_ref.to_string(), "N");
+    let err = get(&reader, "/app1/ifd/{ushort=34855}").expect_err("ISOSpeed is not in IFD0");
+    assert_eq!(
+        err.code(),
+        windows::Win32::Foundation::WINCODEC_ERR_PROPERTYNOTFOUND
+    );
+
+    let title = get(&reader, "/xmp/dc:title").expect("dc:title");
+    assert_eq!(title.to_string(), "Title");
+    let rating = get(&reader, "/xmp/xmp:Rating").expect("xmp:Rating");
+    assert_eq!(rating.to_string(), "3");
+
+    let ifd = get(&reader, "/app1/ifd").expect("The IFD reader");
+    let ifd: IWICMetadataQueryReader = windows::core::IUnknown::try_from(&ifd)
+        .expect("An IUnknown")
+        .cast()
+        .expect("Cast to the query reader");
+    assert_eq!(
+        unsafe { ifd.GetContainerFormat() }.expect("GetContainerFormat"),
+        GUID_MetadataFormatIfd
+    );
+    assert_eq!(
+        enumerate(&ifd),
+        [
+            "/{ushort=271}",
+            "/{ushort=34665}",
+            "/{ushort=34853}",
+            "/exif",
+            "/gps"
+        ]
+    );
+    let iso = get(&ifd, "/exif/{ushort=34855}").expect("ISOSpeed from the IFD reader");
+    assert_eq!(u16::try_from(&iso).ok(), Some(200));
+}
+
+#[test]
+fn still_image_has_no_frame_timing() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let stream = unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream");
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
+    let frame = unsafe { decoder.GetFrame(0) }.expect("Get the first frame");
+    let reader = unsafe { frame.GetMetadataQueryReader() }.expect("Get the query reader");
+
+    let err = unsafe {
+        reader.GetMetadataByName(windows::core::w!("/grctlext/Delay"), std::ptr::null_mut())
+    }
+    .expect_err("No delay for a still image");
+    assert_eq!(
+        err.code(),
+        windows::Win32::Foundation::WINCODEC_ERR_PROPERTYNOTFOUND
+    );
+}
+
+/// The image header of alien.jxl with an animation of 100 ticks per second that loops 3
+/// times, and the frame headers and TOCs of two copies of its frame that last 50 and 25 ticks.
+const ANIMATION_HEADER: [u8; 9] = [0xff, 0x0a, 0xfa, 0x1f, 0x11, 0x84, 0x06, 0x31, 0x01];
+const ANIMATION_FRAME_HEADERS: [[u8; 7]; 2] = [
+    [0x08, 0x06, 0xca, 0x00, 0x00, 0xa0, 0x00],
+    [0x08, 0x06, 0x66, 0x04, 0x00, 0xa0, 0x00],
+];
+/// Where the sections of the frame of alien.jxl start, after its frame header and TOC
+const ALIEN_FRAME_SECTIONS: usize = 12;
+
+#[test]
+fn animation_timing() {
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let sections = &mem[ALIEN_FRAME_SECTIONS..];
+    let animation = [
+        &ANIMATION_HEADER[..],
+        &ANIMATION_FRAME_HEADERS[0],
+        sections,
+        &ANIMATION_FRAME_HEADERS[1],
+        sections,
+    ]
+    .concat();
+
+    let delays = |data: &[u8]| {
+        let decoder = decoder_for(data);
+        let reader = unsafe { decoder.GetMetadataQueryReader() }.expect("Get the query reader");
+        let loops = get(&reader, "/appext/Data").expect("/appext/Data");
+        let mut block = [0u8; 5];
+        unsafe { PropVariantToBuffer(&loops, block.as_mut_ptr() as _, block.len() as u32) }
+            .expect("The NETSCAPE2.0 sub-block");
+        assert_eq!(block, [3, 1, 3, 0, 0], "3 loops");
+
+        let count = unsafe { decoder.GetFrameCount() }.expect("GetFrameCount");
+        (0..count)
+            .map(|index| {
+                let frame = unsafe { decoder.GetFrame(index) }.expect("Get the frame");
+                let reader = unsafe { frame.GetMetadataQueryReader() }.expect("The query reader");
+                let delay = get(&reader, "/grctlext/Delay").expect("/grctlext/Delay");
+                u16::try_from(&delay).expect("A ushort")
+            })
+            .collect::<Vec<_>>()
+    };
+    // In 1/100 seconds, like GIF
+    assert_eq!(delays(&animation), [50, 25]);
+    // The last frame keeps the delay from its header while its pixels are still loading.
+    assert_eq!(delays(&animation[..animation.len() - 20]), [50, 25]);
+}
+
+#[test]
+fn color_contexts() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let stream = unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream");
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
+    let frame = unsafe { decoder.GetFrame(0) }.expect("Get the first frame");
+
+    let mut count = 0u32;
+    unsafe { frame.GetColorContexts(&mut [], &mut count) }.expect("Count the contexts");
+    assert_eq!(count, 1, "count");
+
+    let factory: IWICImagingFactory =
+        unsafe { CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER) }
+            .expect("Create a factory");
+    let context = unsafe { factory.CreateColorContext() }.expect("Create a color context");
+    let mut contexts = [Some(context)];
+    unsafe { frame.GetColorContexts(&mut contexts, &mut count) }.expect("Get the context");
+    let context = contexts[0].as_ref().expect("The context");
+    assert_ne!(
+        unsafe { context.GetType() }.expect("GetType"),
+        WICColorContextUninitialized,
+        "initialized"
+    );
+
+    let err = unsafe { frame.GetColorContexts(&mut [None], &mut count) }.expect_err("A null slot");
+    assert_eq!(err.code(), windows::Win32::Foundation::E_INVALIDARG);
+}
+
+/// The image header of alien.jxl with a linear transfer function and an intensity target of
+/// 1000 nits, which makes it HDR. The frame of alien.jxl starts right after its header, so
+/// that the two can be spliced.
+const LINEAR_HDR_HEADER: [u8; 15] = [
+    0xff, 0x0a, 0xfa, 0x1f, 0x11, 0x40, 0x40, 0xd1, 0x02, 0x3d, 0x06, 0x00, 0x00, 0x00, 0x80,
+];
+const ALIEN_HEADER_SIZE: usize = 6;
+
+/// Returns the frame of alien.jxl, in 32bppBGRA as it is 8 bit, and the same frame decoded
+/// as HDR.
+fn alien_sdr_and_hdr() -> (Vec<u8>, IWICBitmapFrameDecode) {
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let sdr = unsafe { decoder_for(&mem).GetFrame(0) }.expect("Get the SDR frame");
+    assert_eq!(
+        unsafe { sdr.GetPixelFormat() }.expect("GetPixelFormat"),
+        GUID_WICPixelFormat32bppBGRA
+    );
+    let mut pixels: Vec<u8> = vec![0; 1024 * 1024 * 4];
+    unsafe { sdr.CopyPixels(std::ptr::null(), 1024 * 4, &mut pixels) }.expect("Copy pixels");
+
+    let hdr = [&LINEAR_HDR_HEADER[..], &mem[ALIEN_HEADER_SIZE..]].concat();
+    let hdr = unsafe { decoder_for(&hdr).GetFrame(0) }.expect("Get the HDR frame");
+    (pixels, hdr)
+}
+
+#[test]
+fn hdr_half_float() {
+    let (sdr, frame) = alien_sdr_and_hdr();
+    assert_eq!(
+        unsafe { frame.GetPixelFormat() }.expect("GetPixelFormat"),
+        GUID_WICPixelFormat64bppRGBAHalf
+    );
+    let mut pixels: Vec<u8> = vec![0; 1024 * 1024 * 8];
+    unsafe { frame.CopyPixels(std::ptr::null(), 1024 * 8, &mut pixels) }.expect("Copy pixels");
+
+    // scRGB 1.0 is 80 nits, so the linear samples are scaled by 1000 / 80.
+    let mut brightest = 0f32;
+    for (i, (hdr, sdr)) in pixels.chunks_exact(8).zip(sdr.chunks_exact(4)).enumerate() {
+        let [r, g, b, a] =
+            [0, 2, 4, 6].map(|offset| f16::from_le_bytes([hdr[offset], hdr[offset + 1]]).to_f32());
+        for (value, sdr) in [(r, sdr[2]), (g, sdr[1]), (b, sdr[0])] {
+            let expected = sdr as f32 / 255.0 * 1000.0 / 80.0;
+            assert!(
+                (value - expected).abs() <= expected * 0.01 + 0.001,
+                "pixel {i}: {value} for {sdr}"
+            );
+            brightest = brightest.max(value);
+        }
+        assert_eq!(a, 1.0, "pixel {i} alpha");
+    }
+    assert!(
+        brightest > 1.0,
+        "Highlights brighter than SDR white are kept"
+    );
+}
+
+#[test]
+fn hdr_tone_mapped() {
+    let (_, frame) = alien_sdr_and_hdr();
+    let mut hdr: Vec<u8> = vec![0; 1024 * 1024 * 8];
+    unsafe { frame.CopyPixels(std::ptr::null(), 1024 * 8, &mut hdr) }.expect("Copy pixels");
+
+    // 8 bit consumers get tone mapped pixels.
+    let transform: IWICBitmapSourceTransform = frame.cast().expect("Cast to the transform");
+    let mut sdr: Vec<u8> = vec![0; 1024 * 1024 * 4];
+    unsafe {
+        transform.CopyPixels(
+            std::ptr::null(),
+            1024,
+            1024,
+            &GUID_WICPixelFormat32bppBGRA,
+            WICBitmapTransformRotate0,
+            1024 * 4,
+            &mut sdr,
+        )
+    }
+    .expect("Copy tone mapped pixels");
+
+    // The brightest channel of each pixel in nits, and in SDR
+    let mut levels: Vec<(f32, u8)> = hdr
+        .chunks_exact(8)
+        .zip(sdr.chunks_exact(4))
+        .map(|(hdr, sdr)| {
+            let nits = [0, 2, 4]
+                .map(|offset| f16::from_le_bytes([hdr[offset], hdr[offset + 1]]).to_f32() * 80.0)
+                .into_iter()
+                .fold(0f32, f32::max);
+            (nits, sdr[0].max(sdr[1]).max(sdr[2]))
+        })
+        .collect();
+    levels.sort_by(|a, b| a.0.total_cmp(&b.0));
+    assert!(
+        levels.windows(2).all(|pair| pair[0].1 <= pair[1].1),
+        "Brighter pixels stay brighter"
+    );
+
+    // Highlights above the reference white of 203 nits are rolled off below SDR white
+    // instead of clipped to it, except for the peak.
+    let mut highlights: Vec<u8> = levels
+        .iter()
+        .filter(|(nits, _)| *nits > 203.0)
+        .map(|(_, sdr)| *sdr)
+        .collect();
+    highlights.dedup();
+    assert!(highlights.len() > 1, "{:?}", highlights);
+}
+
+/// The image header of alien.jxl with the Display P3 color space, spliced like
+/// LINEAR_HDR_HEADER.
+const DISPLAY_P3_HEADER: [u8; 9] = [0xff, 0x0a, 0xfa, 0x1f, 0x01, 0x01, 0x99, 0xdc, 0x08];
+
+#[test]
+fn target_color_space() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+    let factory: IWICImagingFactory =
+        unsafe { CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER) }
+            .expect("Create a factory");
+
+    // The pixels and whether the color context is a profile, as opposed to Exif sRGB
+    let decode = |data: &[u8], color_space: &str| {
+        let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::with_color_space(color_space)
+            .expect("A known color space")
+            .into();
+        unsafe { decoder.Initialize(&stream(data), WICDecodeOptions(0)) }
+            .expect("Initialize the decoder");
+        let frame = unsafe { decoder.GetFrame(0) }.expect("Get the first frame");
+
+        let context = unsafe { factory.CreateColorContext() }.expect("Create a color context");
+        let mut contexts = [Some(context)];
+        let mut count = 0u32;
+        unsafe { frame.GetColorContexts(&mut contexts, &mut count) }.expect("Get the context");
+        let context = contexts[0].as_ref().expect("The context");
+        let profile = match unsafe { context.GetType() }.expect("GetType") {
+            WICColorContextProfile => true,
+            WICColorContextExifColorSpace => {
+                assert_eq!(
+                    unsafe { context.GetExifColorSpace() }.expect("Exif color space"),
+                    1
+                );
+                false
+            }
+            other => panic!("{:?}", other),
+        };
+
+        let format = unsafe { frame.GetPixelFormat() }.expect("GetPixelFormat");
+        let stride = 1024 * bytes_per_pixel(&format);
+        let mut pixels: Vec<u8> = vec![0; stride * 1024];
+        unsafe { frame.CopyPixels(std::ptr::null(), stride as u32, &mut pixels) }
+            .expect("Copy pixels");
+        (pixels, profile)
+    };
+
+    let srgb = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let p3 = [&DISPLAY_P3_HEADER[..], &srgb[ALIEN_HEADER_SIZE..]].concat();
+    let (srgb_pixels, profile) = decode(&srgb, "original");
+    assert!(!profile, "An sRGB original");
+
+    // The original keeps the samples as they are encoded, and has a profile unless it is sRGB.
+    let (pixels, profile) = decode(&p3, "original");
+    assert!(pixels == srgb_pixels, "Display P3 original pixels");
+    assert!(profile, "Display P3 original profile");
+
+    // Converting changes the colors, and the color context follows.
+    let (pixels, profile) = decode(&p3, "srgb");
+    assert!(pixels != srgb_pixels, "Display P3 in sRGB");
+    assert!(!profile, "Display P3 in sRGB");
+    let (pixels, profile) = decode(&srgb, "srgb");
+    assert!(pixels == srgb_pixels, "sRGB in sRGB");
+    assert!(!profile, "sRGB in sRGB");
+    let (pixels, profile) = decode(&srgb, "displayp3");
+    assert!(pixels != srgb_pixels, "sRGB in Display P3");
+    assert!(profile, "sRGB in Display P3");
+
+    assert!(JXLWICBitmapDecoder::with_color_space("adobergb").is_none());
+}
+
+#[test]
+fn truncated() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let truncated = &mem[..mem.len() * 3 / 4];
+    let stream = unsafe { SHCreateMemStream(Some(truncated)) }.expect("Create an IStream");
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }
+        .expect("Initialize with a truncated file");
+    let count = unsafe { decoder.GetFrameCount() }.expect("GetFrameCount");
+    assert_eq!(count, 1, "the partially loaded frame");
+
+    let frame = unsafe { decoder.GetFrame(0) }.expect("Get the partial frame");
+    let source = unsafe { WICConvertBitmapSource(&GUID_WICPixelFormat32bppPRGBA, &frame) }
+        .expect("Create a bitmap source");
+    let mut pixels: Vec<u8> = vec![0; 1024 * 1024 * 4];
+    unsafe { source.CopyPixels(std::ptr::null(), 1024 * 4, &mut pixels) }
+        .expect("Copy pixels of the partial frame");
+
+    let readers = [
+        unsafe { decoder.GetMetadataQueryReader() }.expect("Get the decoder query reader"),
+        unsafe { frame.GetMetadataQueryReader() }.expect("Get the frame query reader"),
+    ];
+    for reader in &readers {
+        let incomplete = get(reader, "/Incomplete").expect("The incomplete flag");
+        assert!(bool::try_from(&incomplete).expect("A bool"), "incomplete");
+        assert_eq!(enumerate(reader)[0], "/Incomplete", "enumerated");
+    }
+}
+
+#[test]
+fn shared_stream() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    // The image starts after some unrelated bytes, as if embedded in another file.
+    let mut mem = vec![0u8; 16];
+    mem.extend(std::fs::read("tests/alien.jxl").expect("Read the test file"));
+    let stream = unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream");
+    unsafe { stream.Seek(16, STREAM_SEEK_SET, None) }.expect("Seek to the image");
+
+    for _ in 0..2 {
+        let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+        unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }
+            .expect("Initialize with the shared stream");
+        let mut position = 0u64;
+        unsafe { stream.Seek(0, STREAM_SEEK_CUR, Some(&mut position as *mut _)) }
+            .expect("Get the position");
+        assert_eq!(position, 16, "the position is restored");
+    }
+}
+
+#[test]
+fn cancelled() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let stream = unsafe { SHCreateMemStream(Some(&mem[..])) }.expect("Create an IStream");
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let decoder: IWICBitmapDecoder =
+        JXLWICBitmapDecoder::with_cancel_flag(cancelled.clone()).into();
+    unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }.expect("Initialize the decoder");
+    let frame = unsafe { decoder.GetFrame(0) }.expect("Get the first frame");
+
+    cancelled.store(true, Ordering::Relaxed);
+    let mut pixels: Vec<u8> = vec![0; 1024 * 1024 * 4];
+    let err = unsafe { frame.CopyPixels(std::ptr::null(), 1024 * 4, &mut pixels) }
+        .expect_err("Copy pixels after cancelling");
+    assert_eq!(err.code(), windows::Win32::Foundation::E_ABORT);
+
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::with_cancel_flag(cancelled).into();
+    let err = unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }
+        .expect_err("Initialize after cancelling");
+    assert_eq!(err.code(), windows::Win32::Foundation::E_ABORT);
+
+    // As a COM host cancels
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    let control: IJXLDecodeControl = decoder.cast().expect("Cast to the decode control");
+    unsafe { control.Cancel() }.ok().expect("Cancel");
+    let err = unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }
+        .expect_err("Initialize after cancelling through COM");
+    assert_eq!(err.code(), windows::Win32::Foundation::E_ABORT);
+}
+
+/// Reads the first `fast` bytes at once, then stalls like a slow network share, sleeping
+/// before every byte. Without `sized`, the decoder can only read it in chunks.
+#[implement(Windows::Win32::System::Com::IStream)]
+struct SlowStream {
+    data: Vec<u8>,
+    position: Cell<usize>,
+    fast: usize,
+    sized: bool,
+}
+
+impl ISequentialStream_Impl for SlowStream_Impl {
+    fn Read(&self, pv: *mut core::ffi::c_void, cb: u32, pcbread: *mut u32) -> HRESULT {
+        let position = self.position.get();
+        let available = if position < self.fast {
+            self.fast - position
+        } else {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            1
+        };
+        let len = (cb as usize).min(available).min(self.data.len() - position);
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.data[position..].as_ptr(), pv as *mut u8, len);
+            if let Some(read) = pcbread.as_mut() {
+                *read = len as u32;
+            }
+        }
+        self.position.set(position + len);
+        if len < cb as usize { S_FALSE } else { S_OK }
+    }
+
+    fn Write(&self, _pv: *const core::ffi::c_void, _cb: u32, _pcbwritten: *mut u32) -> HRESULT {
+        E_NOTIMPL
+    }
+}
+
+impl IStream_Impl for SlowStream_Impl {
+    fn Seek(
+        &self,
+        dlibmove: i64,
+        dworigin: STREAM_SEEK,
+        plibnewposition: *mut u64,
+    ) -> windows::core::Result<()> {
+        let origin = match dworigin {
+            STREAM_SEEK_SET => 0,
+            STREAM_SEEK_CUR => self.position.get() as i64,
+            _ => self.data.len() as i64,
+        };
+        let position = (origin + dlibmove).clamp(0, self.data.len() as i64);
+        self.position.set(position as usize);
+        if let Some(new_position) = unsafe { plibnewposition.as_mut() } {
+            *new_position = position as u64;
+        }
+        Ok(())
+    }
+
+    fn SetSize(&self, _libnewsize: u64) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn CopyTo(
+        &self,
+        _pstm: Option<&IStream>,
+        _cb: u64,
+        _pcbread: *mut u64,
+        _pcbwritten: *mut u64,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Commit(&self, _grfcommitflags: &STGC) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Revert(&self) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn LockRegion(
+        &self,
+        _liboffset: u64,
+        _cb: u64,
+        _dwlocktype: &LOCKTYPE,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn UnlockRegion(
+        &self,
+        _liboffset: u64,
+        _cb: u64,
+        _dwlocktype: u32,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Stat(&self, pstatstg: *mut STATSTG, _grfstatflag: &STATFLAG) -> windows::core::Result<()> {
+        if !self.sized {
+            return Err(E_NOTIMPL.into());
+        }
+        unsafe {
+            *pstatstg = STATSTG {
+                cbSize: self.data.len() as u64,
+                ..Default::default()
+            }
+        };
+        Ok(())
+    }
+
+    fn Clone(&self) -> windows::core::Result<IStream> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+/// Initializes a decoder with a 20 ms timeout from a slow stream of alien.jxl.
+fn slow_decoder(fast: usize, sized: bool) -> (IWICBitmapDecoder, windows::core::Result<()>) {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+    let data = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let stream: IStream = SlowStream {
+        fast: fast.min(data.len()),
+        data,
+        position: Cell::new(0),
+        sized,
+    }
+    .into();
+    let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+    let control: IJXLDecodeControl = decoder.cast().expect("Cast to the decode control");
+    unsafe { control.SetDecodeTimeout(20) }
+        .ok()
+        .expect("SetDecodeTimeout");
+    let result = unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) };
+    (decoder, result)
+}
+
+#[test]
+fn timeout() {
+    // Out of time before the header is in
+    let (_, result) = slow_decoder(0, true);
+    let err = result.expect_err("Initialize with a slow stream");
+    assert_eq!(err.code(), ERROR_TIMEOUT.to_hresult());
+
+    // Out of time after the header and part of the frame, as much as the truncated test has
+    let len = std::fs::read("tests/alien.jxl")
+        .expect("Read the test file")
+        .len();
+    let (decoder, result) = slow_decoder(len * 3 / 4, false);
+    result.expect("Initialize with the partial image");
+    let count = unsafe { decoder.GetFrameCount() }.expect("GetFrameCount");
+    assert_eq!(count, 1, "the partially loaded frame");
+
+    let reader = unsafe { decoder.GetMetadataQueryReader() }.expect("Get the query reader");
+    let incomplete = get(&reader, "/Incomplete").expect("The incomplete flag");
+    assert!(bool::try_from(&incomplete).expect("A bool"), "incomplete");
+
+    let frame = unsafe { decoder.GetFrame(0) }.expect("Get the partial frame");
+    let source = unsafe { WICConvertBitmapSource(&GUID_WICPixelFormat32bppPRGBA, &frame) }
+        .expect("Create a bitmap source");
+    let mut pixels: Vec<u8> = vec![0; 1024 * 1024 * 4];
+    unsafe { source.CopyPixels(std::ptr::null(), 1024 * 4, &mut pixels) }
+        .expect("Copy pixels of the partial frame");
+}
+
+#[test]
+fn exif_properties() {
+    let exif = exif_payload(
+        &[ascii(0x010f, "Maker"), ascii(0x0110, "Model")],
+        &[
+            rationals(0x829a, &[(1, 250)]),
+            rationals(0x829d, &[(28, 10)]),
+            short(0x8827, 200),
+            short(0xa405, 50),
+        ],
+        &[
+            ascii(0x0001, "N"),
+            rationals(0x0002, &[(35, 1), (30, 1), (0, 1)]),
+            ascii(0x0003, "W"),
+            rationals(0x0004, &[(139, 1), (45, 1), (0, 1)]),
+        ],
+    );
+    let codestream = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let store = property_store(&container(&codestream, &[(b"Exif", &exif)]));
+    let value = |fmtid: u128, pid: u32| {
+        let key = PROPERTYKEY {
+            fmtid: GUID::from_u128(fmtid),
+            pid,
+        };
+        unsafe { store.GetValue(&key) }.expect("GetValue")
+    };
+
+    const PHOTO: u128 = 0x14B81DA1_0135_4D31_96D9_6CBFC9671A99;
+    assert_eq!(value(PHOTO, 271).to_string(), "Maker", "CameraManufacturer");
+    assert_eq!(value(PHOTO, 272).to_string(), "Model", "CameraModel");
+    assert_eq!(
+        f64::try_from(&value(PHOTO, 33434)).ok(),
+        Some(0.004),
+        "ExposureTime"
+    );
+    assert_eq!(
+        f64::try_from(&value(PHOTO, 33437)).ok(),
+        Some(2.8),
+        "FNumber"
+    );
+    assert_eq!(
+        u16::try_from(&value(PHOTO, 34855)).ok(),
+        Some(200),
+        "ISOSpeed"
+    );
+    assert_eq!(
+        u16::try_from(&value(0xA0E74609_B84D_4F49_B860_462BD9971F98, 100)).ok(),
+        Some(50),
+        "FocalLengthInFilm"
+    );
+    assert!(value(PHOTO, 0xa405).is_empty(), "not keyed by its tag");
+
+    assert_eq!(
+        f64::try_from(&value(0x0F55CDE2_4F49_450D_92C1_DCD16301B1B7, 100)).ok(),
+        Some(35.5),
+        "LatitudeDecimal"
+    );
+    assert_eq!(
+        f64::try_from(&value(0x4679C1B5_844D_4590_BAF5_F322231F1B81, 100)).ok(),
+        Some(-139.75),
+        "LongitudeDecimal"
+    );
+}
+
+#[test]
+fn resolution() {
+    let codestream = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let dpi = |data: &[u8]| {
+        let frame = unsafe { decoder_for(data).GetFrame(0) }.expect("Get the first frame");
+        let (mut x, mut y) = (0f64, 0f64);
+        unsafe { frame.GetResolution(&mut x, &mut y) }.expect("GetResolution");
+        (x, y)
+    };
+    let with_exif = |ifd0: &[Entry]| {
+        let exif = exif_payload(ifd0, &[], &[]);
+        dpi(&container(&codestream, &[(b"Exif", &exif)]))
+    };
+
+    assert_eq!(dpi(&codestream), (96.0, 96.0), "No Exif");
+    assert_eq!(
+        with_exif(&[
+            rationals(0x011a, &[(300, 1)]),
+            rationals(0x011b, &[(150, 1)]),
+            short(0x0128, 2),
+        ]),
+        (300.0, 150.0),
+        "Inches"
+    );
+    assert_eq!(
+        with_exif(&[rationals(0x011a, &[(72, 1)]), rationals(0x011b, &[(72, 1)])]),
+        (72.0, 72.0),
+        "Inches by default"
+    );
+    assert_eq!(
+        with_exif(&[
+            rationals(0x011a, &[(100, 1)]),
+            rationals(0x011b, &[(100, 1)]),
+            short(0x0128, 3),
+        ]),
+        (254.0, 254.0),
+        "Centimeters"
+    );
+    assert_eq!(
+        with_exif(&[
+            rationals(0x011a, &[(1, 1)]),
+            rationals(0x011b, &[(2, 1)]),
+            short(0x0128, 1),
+        ]),
+        (96.0, 96.0),
+        "Only an aspect ratio"
+    );
+    assert_eq!(
+        with_exif(&[ascii(0x010f, "Maker")]),
+        (96.0, 96.0),
+        "No resolution tags"
+    );
+}
+
+/// The header of an 8 x 8 XYB image, 8 bit with an alpha channel, and nothing else
+const ALPHA_HEADER: [u8; 5] = [0xff, 0x0a, 0x41, 0xc0, 0x4e];
+
+#[test]
+fn alpha_properties() {
+    let bit_depth = PROPERTYKEY {
+        fmtid: GUID::from_u128(0x6444048F_4C8B_11D1_8B70_080036B11A03),
+        pid: 7,
+    };
+    let values = |data: &[u8]| {
+        let store = property_store(data);
+        let bit_depth = unsafe { store.GetValue(&bit_depth) }.expect("BitDepth");
+        let has_alpha = unsafe { store.GetValue(&PKEY_HAS_ALPHA) }.expect("HasAlpha");
+        (
+            u32::try_from(&bit_depth).expect("A number"),
+            bool::try_from(&has_alpha).expect("A boolean"),
+        )
+    };
+
+    // 8 bit RGB
+    let alien = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    assert_eq!(values(&alien), (24, false), "alien.jxl");
+    // With 8 more bits for alpha. The header is all the property store reads.
+    assert_eq!(values(&ALPHA_HEADER), (32, true), "with alpha");
+}
+
+/// A memory stream that saves the way the property system's safe save does, into a
+/// destination stream that replaces the file once committed.
+#[implement(
+    Windows::Win32::System::Com::IStream,
+    Windows::Win32::UI::Shell::IDestinationStreamFactory
+)]
+struct SafeSaveStream {
+    inner: IStream,
+    destination: IStream,
+}
+
+impl ISequentialStream_Impl for SafeSaveStream_Impl {
+    fn Read(&self, pv: *mut core::ffi::c_void, cb: u32, pcbread: *mut u32) -> HRESULT {
+        unsafe { self.inner.Read(pv, cb, Some(pcbread)) }
+    }
+
+    fn Write(&self, pv: *const core::ffi::c_void, cb: u32, pcbwritten: *mut u32) -> HRESULT {
+        unsafe { self.inner.Write(pv, cb, Some(pcbwritten)) }
+    }
+}
+
+impl IStream_Impl for SafeSaveStream_Impl {
+    fn Seek(
+        &self,
+        dlibmove: i64,
+        dworigin: STREAM_SEEK,
+        plibnewposition: *mut u64,
+    ) -> windows::core::Result<()> {
+        unsafe { self.inner.Seek(dlibmove, dworigin, Some(plibnewposition)) }
+    }
+
+    fn SetSize(&self, libnewsize: u64) -> windows::core::Result<()> {
+        unsafe { self.inner.SetSize(libnewsize) }
+    }
+
+    fn CopyTo(
+        &self,
+        _pstm: Option<&IStream>,
+        _cb: u64,
+        _pcbread: *mut u64,
+        _pcbwritten: *mut u64,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Commit(&self, grfcommitflags: &STGC) -> windows::core::Result<()> {
+        unsafe { self.inner.Commit(*grfcommitflags) }
+    }
+
+    fn Revert(&self) -> windows::core::Result<()> {
+        unsafe { self.inner.Revert() }
+    }
+
+    fn LockRegion(
+        &self,
+        _liboffset: u64,
+        _cb: u64,
+        _dwlocktype: &LOCKTYPE,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn UnlockRegion(
+        &self,
+        _liboffset: u64,
+        _cb: u64,
+        _dwlocktype: u32,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Stat(&self, pstatstg: *mut STATSTG, grfstatflag: &STATFLAG) -> windows::core::Result<()> {
+        unsafe { self.inner.Stat(pstatstg, *grfstatflag) }
+    }
+
+    fn Clone(&self) -> windows::core::Result<IStream> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+impl IDestinationStreamFactory_Impl for SafeSaveStream_Impl {
+    fn GetDestinationStream(&self) -> windows::core::Result<IStream> {
+        Ok(self.destination.clone())
+    }
+}
+
+/// A property store opened for writing, and the stream it saves into.
+fn writable_property_store(data: &[u8]) -> (IPropertyStore, IStream) {
+    let inner = stream(data);
+    let destination = unsafe { SHCreateMemStream(None) }.expect("Create the destination");
+    let stream: IStream = SafeSaveStream {
+        inner,
+        destination: destination.clone(),
+    }
+    .into();
+    let store: IInitializeWithStream = JXLPropertyStore::default().into();
+    unsafe { store.Initialize(&stream, STGM_READWRITE.0) }.expect("Initialize for writing");
+    (store.cast().expect("Cast to IPropertyStore"), destination)
+}
+
+/// IsPropertyWritable returns S_FALSE for a read-only property, which the wrapper takes
+/// as success.
+fn is_writable(store: &IPropertyStore, key: &PROPERTYKEY) -> bool {
+    let capabilities: IPropertyStoreCapabilities =
+        store.cast().expect("Cast to IPropertyStoreCapabilities");
+    let result = unsafe {
+        (Interface::vtable(&capabilities).IsPropertyWritable)(capabilities.as_raw(), key)
+    };
+    result == S_OK
+}
+
+fn strings(values: &[&str]) -> PROPVARIANT {
+    let values: Vec<HSTRING> = values.iter().map(|value| HSTRING::from(*value)).collect();
+    let pointers: Vec<PCWSTR> = values.iter().map(|value| PCWSTR(value.as_ptr())).collect();
+    unsafe { InitPropVariantFromStringVector(Some(&pointers)) }.expect("A string vector")
+}
+
+const SUMMARY_INFORMATION: u128 = 0xF29F85E0_4FF9_1068_AB91_08002B27B3D9;
+const TITLE: PROPERTYKEY = PROPERTYKEY {
+    fmtid: GUID::from_u128(SUMMARY_INFORMATION),
+    pid: 2,
+};
+const KEYWORDS: PROPERTYKEY = PROPERTYKEY {
+    fmtid: GUID::from_u128(SUMMARY_INFORMATION),
+    pid: 5,
+};
+const RATING: PROPERTYKEY = PROPERTYKEY {
+    fmtid: GUID::from_u128(0x64440492_4C8B_11D1_8B70_080036B11A03),
+    pid: 9,
+};
+
+#[test]
+fn property_write_round_trip() {
+    let codestream = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let (store, destination) = writable_property_store(&codestream);
+    assert!(is_writable(&store, &TITLE), "Title");
+    let width = PROPERTYKEY {
+        fmtid: GUID::from_u128(0x6444048F_4C8B_11D1_8B70_080036B11A03),
+        pid: 3,
+    };
+    assert!(!is_writable(&store, &width), "Width");
+
+    unsafe { store.SetValue(&TITLE, &strings(&["New title"])) }.expect("Set the title");
+    unsafe { store.SetValue(&KEYWORDS, &strings(&["alien", "art"])) }.expect("Set the keywords");
+    unsafe { store.SetValue(&RATING, &PROPVARIANT::from(75u32)) }.expect("Set the rating");
+    let err =
+        unsafe { store.SetValue(&width, &PROPVARIANT::from(1u32)) }.expect_err("Set the width");
+    assert_eq!(err.code(), STG_E_ACCESSDENIED);
+    unsafe { store.Commit() }.expect("Commit");
+
+    let mut size = 0u64;
+    unsafe { destination.Seek(0, STREAM_SEEK_END, Some(&mut size as *mut _)) }
+        .expect("Get the size");
+    unsafe { destination.Seek(0, STREAM_SEEK_SET, None) }.expect("Rewind");
+    let mut saved = vec![0u8; size as usize];
+    let mut read = 0u32;
+    unsafe { destination.Read(saved.as_mut_ptr() as _, size as u32, Some(&mut read)) }
+        .ok()
+        .expect("Read the saved file");
+    assert_eq!(read as u64, size);
+
+    // The saved file is a container with the codestream and the new XMP.
+    let store = property_store(&saved);
+    let value = |key: &PROPERTYKEY| unsafe { store.GetValue(key) }.expect("GetValue");
+    assert_eq!(value(&TITLE).to_string(), "New title");
+    assert_eq!(u32::try_from(&value(&RATING)).ok(), Some(75));
+    let keywords = value(&KEYWORDS);
+    assert_eq!(
+        unsafe { PropVariantGetElementCount(&keywords) },
+        2,
+        "keywords"
+    );
+    let decoder = decoder_for(&saved);
+    let frame = unsafe { decoder.GetFrame(0) }.expect("Decode the saved file");
+    let mut pixels: Vec<u8> = vec![0; 1024 * 1024 * 4];
+    unsafe { frame.CopyPixels(std::ptr::null(), 1024 * 4, &mut pixels) }
+        .expect("Copy pixels of the saved file");
+}
+
+/// The items of a string or a vector of strings.
+fn string_items(value: &PROPVARIANT) -> Vec<String> {
+    (0..unsafe { PropVariantGetElementCount(value) })
+        .map(|index| {
+            let item = unsafe { PropVariantGetStringElem(value, index) }.expect("An item");
+            let text = unsafe { item.to_string() }.expect("UTF-16");
+            unsafe { CoTaskMemFree(Some(item.0 as _)) };
+            text
+        })
+        .collect()
+}
+
+#[test]
+fn xmp_properties() {
+    let xmp = br#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="4">
+   <dc:title xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <rdf:Alt>
+     <rdf:li xml:lang="de">Ausserirdischer</rdf:li>
+     <rdf:li xml:lang="x-default">Alien</rdf:li>
+    </rdf:Alt>
+   </dc:title>
+   <dc:creator xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <rdf:Seq><rdf:li>First Author</rdf:li><rdf:li>Second Author</rdf:li></rdf:Seq>
+   </dc:creator>
+   <elements:subject xmlns:elements="http://purl.org/dc/elements/1.1/">
+    <rdf:Bag><rdf:li>alien</rdf:li><rdf:li>green &amp; art</rdf:li></rdf:Bag>
+   </elements:subject>
+   <dc:description xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <rdf:Alt><rdf:li xml:lang="x-default">From outer space</rdf:li></rdf:Alt>
+   </dc:description>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="r"?>"#;
+    let codestream = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let store = property_store(&container(&codestream, &[(b"xml ", xmp)]));
+    let value = |key: &PROPERTYKEY| unsafe { store.GetValue(key) }.expect("GetValue");
+    let author = PROPERTYKEY {
+        fmtid: TITLE.fmtid,
+        pid: 4,
+    };
+    let comment = PROPERTYKEY {
+        fmtid: TITLE.fmtid,
+        pid: 6,
+    };
+
+    assert_eq!(value(&TITLE).to_string(), "Alien", "Title");
+    assert_eq!(
+        string_items(&value(&author)),
+        ["First Author", "Second Author"],
+        "Author"
+    );
+    // Matched by the namespace, whatever the prefix
+    assert_eq!(
+        string_items(&value(&KEYWORDS)),
+        ["alien", "green & art"],
+        "Keywords"
+    );
+    assert_eq!(value(&comment).to_string(), "From outer space", "Comment");
+    assert_eq!(u32::try_from(&value(&RATING)).ok(), Some(75), "Rating");
+
+    // Nothing is made up without XMP.
+    let store = property_store(&codestream);
+    let value = |key: &PROPERTYKEY| unsafe { store.GetValue(key) }.expect("GetValue");
+    for key in [TITLE, author, KEYWORDS, comment, RATING] {
+        assert!(value(&key).is_empty(), "{:?}", key);
+    }
+}
+
+#[test]
+fn property_read_only() {
+    let codestream = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let store = property_store(&codestream);
+    assert!(!is_writable(&store, &TITLE), "opened for reading");
+    let err =
+        unsafe { store.SetValue(&TITLE, &strings(&["New title"])) }.expect_err("Set the title");
+    assert_eq!(err.code(), STG_E_ACCESSDENIED);
+
+    // The XMP of a recompressed JPEG has to keep its size.
+    let (store, _) =
+        writable_property_store(&container(&codestream, &[(b"jbrd", b"reconstruction")]));
+    assert!(!is_writable(&store, &TITLE), "JPEG reconstruction data");
+    let err = unsafe { store.SetValue(&TITLE, &strings(&["New title"])) }
+        .expect_err("Set the title with JPEG reconstruction data");
+    assert_eq!(err.code(), STG_E_ACCESSDENIED);
+}
+
+#[test]
+fn file_stream() {
+    unsafe { CoInitialize(None) }.ok().expect("CoInitialize");
+
+    let mem = std::fs::read("tests/alien.jxl").expect("Read the test file");
+    let copy_pixels = |decoder: &IWICBitmapDecoder| {
+        let frame = unsafe { decoder.GetFrame(0) }.expect("Get the first frame");
+        let mut pixels: Vec<u8> = vec![0; 1024 * 1024 * 8];
+        unsafe { frame.CopyPixels(std::ptr::null(), 1024 * 8, &mut pixels) }.expect("Copy pixels");
+        pixels
+    };
+    let expected = copy_pixels(&decoder_for(&mem));
+
+    let path = std::env::temp_dir().join(format!("jxl-winthumb-{}.jxl", std::process::id()));
+    std::fs::write(&path, &mem).expect("Write the file");
+    // The file is mapped when only read, and read through the stream while it can be written.
+    for mode in [STGM_READ, STGM_READWRITE] {
+        let stream = unsafe {
+            SHCreateStreamOnFileEx(
+                &HSTRING::from(path.as_os_str()),
+                mode.0,
+                0,
+                BOOL::from(false),
+                None::<&IStream>,
+            )
+        }
+        .expect("Create a file IStream");
+        let decoder: IWICBitmapDecoder = JXLWICBitmapDecoder::default().into();
+        unsafe { decoder.Initialize(&stream, WICDecodeOptions(0)) }
+            .expect("Initialize the decoder");
+        assert!(copy_pixels(&decoder) == expected, "{:?}", mode);
+    }
+    std::fs::remove_file(&path).expect("Remove the file");
+}